@@ -1,12 +1,15 @@
 use serde::Serialize;
 use crate::blockchain::Blockchain;
-use crate::miner::Handle as MinerHandle;
+use crate::miner::{admit_transaction, Handle as MinerHandle, Mempool};
 use crate::network::server::Handle as NetworkServerHandle;
+use crate::network::download::BlockDownload;
 use crate::network::message::Message;
+use crate::network::peer::Direction;
 
 use crate::generator::generator::TransactionGenerator;
 use crate::types::block::BlockState;
 use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::SignedTransaction;
 
 use log::info;
 use std::collections::HashMap;
@@ -19,11 +22,19 @@ use url::Url;
 
 pub struct Server {
     handle: HTTPServer,
-    miner: MinerHandle,
-    tx_generator: TransactionGenerator,
-    network: NetworkServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    block_state: Arc<Mutex<BlockState>>
+    context: ApiContext,
+}
+
+/// Handles to the parts of the node the API reads from and controls
+#[derive(Clone)]
+pub struct ApiContext {
+    pub miner: MinerHandle,
+    pub tx_generator: TransactionGenerator,
+    pub network: NetworkServerHandle,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub block_state: Arc<Mutex<BlockState>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub download: Arc<Mutex<BlockDownload>>,
 }
 
 #[derive(Serialize)]
@@ -32,6 +43,32 @@ struct ApiResponse {
     message: String,
 }
 
+/// A banned peer, as listed by `/network/bans`
+#[derive(Serialize)]
+struct BanEntry {
+    address: String,
+    /// End of the ban, in seconds since the Unix epoch
+    banned_until: u64,
+}
+
+/// A connected peer, as listed by `/network/peers`
+#[derive(Serialize)]
+struct PeerEntry {
+    address: String,
+    /// "inbound" or "outbound"
+    direction: &'static str,
+    /// Hex of the Ed25519 identity key the peer proved it holds, on an encrypted connection
+    static_key: Option<String>,
+    /// When the connection was established, in seconds since the Unix epoch
+    connected_at: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    messages_in: u64,
+    messages_out: u64,
+    /// Round trip of the last ping the peer answered, in milliseconds
+    ping_rtt_ms: Option<f64>,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -54,30 +91,20 @@ macro_rules! respond_json {
 }
 
 impl Server {
-    pub fn start(
-        addr: std::net::SocketAddr,
-        miner: &MinerHandle,
-        tx_generator: &TransactionGenerator,
-        network: &NetworkServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
-        block_state: &Arc<Mutex<BlockState>>
-    ) {
+    pub fn start(addr: std::net::SocketAddr, context: ApiContext) {
         let handle = HTTPServer::http(&addr).unwrap();
-        let server = Self {
-            handle,
-            miner: miner.clone(),
-            tx_generator: tx_generator.clone(),
-            network: network.clone(),
-            blockchain: Arc::clone(blockchain),
-            block_state: Arc::clone(block_state)
-        };
+        let server = Self { handle, context };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
-                let miner = server.miner.clone();
-                let tx_generator = server.tx_generator.clone();
-                let network = server.network.clone();
-                let blockchain = Arc::clone(&server.blockchain);
-                let block_state_map = Arc::clone(&server.block_state);
+                let ApiContext {
+                    miner,
+                    tx_generator,
+                    network,
+                    blockchain,
+                    block_state: block_state_map,
+                    mempool,
+                    download,
+                } = server.context.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -138,9 +165,193 @@ impl Server {
                             respond_result!(req, true, "ok");
                         }
                         "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // ping a single peer if one is given, every peer otherwise
+                            match params.get("peer") {
+                                Some(peer) => {
+                                    let peer = match peer.parse::<std::net::SocketAddr>() {
+                                        Ok(v) => v,
+                                        Err(e) => {
+                                            respond_result!(req, false, format!("error parsing peer: {}", e));
+                                            return;
+                                        }
+                                    };
+                                    if let Err(e) = network.send_to_peer(peer, Message::Ping(String::from("Test ping"))) {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                }
+                                None => network.broadcast(Message::Ping(String::from("Test ping"))),
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/peers" => {
+                            let peers: Vec<PeerEntry> = network
+                                .peers()
+                                .into_iter()
+                                .map(|peer| PeerEntry {
+                                    address: peer.addr.to_string(),
+                                    direction: match peer.direction {
+                                        Direction::Incoming => "inbound",
+                                        Direction::Outgoing => "outbound",
+                                    },
+                                    static_key: peer.static_key.map(hex::encode),
+                                    connected_at: peer.connected_at,
+                                    bytes_in: peer.traffic.bytes_in,
+                                    bytes_out: peer.traffic.bytes_out,
+                                    messages_in: peer.traffic.messages_in,
+                                    messages_out: peer.traffic.messages_out,
+                                    ping_rtt_ms: peer.traffic.ping_rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                                })
+                                .collect();
+                            respond_json!(req, peers);
+                        }
+                        "/network/connect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let addr = match addr.parse::<std::net::SocketAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                            };
+                            if let Err(e) = network.connect(addr) {
+                                respond_result!(req, false, format!("error connecting to {}: {}", addr, e));
+                                return;
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/disconnect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let addr = match addr.parse::<std::net::SocketAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                            };
+                            if !network.disconnect(addr) {
+                                respond_result!(req, false, format!("no peer connected at {}", addr));
+                                return;
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/bans" => {
+                            let bans: Vec<BanEntry> = network
+                                .bans()
+                                .into_iter()
+                                .map(|(addr, until)| BanEntry { address: addr.to_string(), banned_until: until })
+                                .collect();
+                            respond_json!(req, bans);
+                        }
+                        "/network/ban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let addr = match parse_ip(addr) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                            };
+                            // ban for the configured duration unless one is given, in seconds
+                            let duration = match params.get("duration").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => Some(std::time::Duration::from_secs(v)),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing duration: {}", e));
+                                    return;
+                                }
+                                None => None,
+                            };
+                            network.ban(addr, duration);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/unban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let addr = match parse_ip(addr) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                            };
+                            if !network.unban(addr) {
+                                respond_result!(req, false, format!("{} is not banned", addr));
+                                return;
+                            }
                             respond_result!(req, true, "ok");
                         }
+                        "/transaction/submit" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // the bincode encoding of a `SignedTransaction`, in hex
+                            let tx = match params.get("tx") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx");
+                                    return;
+                                }
+                            };
+                            let tx = match hex::decode(tx).map_err(|e| e.to_string()).and_then(|bytes| {
+                                bincode::deserialize::<SignedTransaction>(&bytes).map_err(|e| e.to_string())
+                            }) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing tx: {}", e));
+                                    return;
+                                }
+                            };
+                            if let Err(e) = admit_transaction(&tx, &blockchain, &block_state_map, &mempool) {
+                                respond_result!(req, false, format!("transaction rejected: {}", e));
+                                return;
+                            }
+                            network.broadcast(Message::NewTransactionHashes(vec![tx.hash()]));
+                            respond_result!(req, true, tx.hash());
+                        }
+                        "/mempool/stats" => {
+                            let stats = mempool.lock().unwrap().stats();
+                            respond_json!(req, stats);
+                        }
+                        "/blockchain/sync" => {
+                            let (header_height, block_height) = {
+                                let blockchain = blockchain.lock().unwrap();
+                                (blockchain.headers.best_height(), blockchain.tip_height())
+                            };
+                            let progress = download.lock().unwrap().progress(header_height, block_height);
+                            respond_json!(req, progress);
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -232,8 +443,8 @@ impl Server {
                                     // Format and return the state of the block
                                     let state: Vec<String> = block_state
                                         .iter()
-                                        .map(|(address, (nonce, balance))| {
-                                            format!("({}, {}, {})", address, nonce, balance)
+                                        .map(|(address, account)| {
+                                            format!("({}, {}, {})", address, account.nonce, account.balance)
                                         })
                                         .collect();
                                     
@@ -269,3 +480,9 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+/// Bans are per IP; a full socket address is accepted too and stands for its IP
+fn parse_ip(addr: &str) -> Result<std::net::IpAddr, std::net::AddrParseError> {
+    addr.parse::<std::net::IpAddr>()
+        .or_else(|e| addr.parse::<std::net::SocketAddr>().map(|addr| addr.ip()).map_err(|_| e))
+}
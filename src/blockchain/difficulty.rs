@@ -0,0 +1,69 @@
+use crate::types::hash::H256;
+use num_bigint::BigUint;
+
+/// Number of blocks between two difficulty adjustments
+pub const RETARGET_INTERVAL: u64 = 20;
+/// Block interval the retargeting aims for, in milliseconds
+pub const TARGET_BLOCK_TIME: u128 = 5_000;
+/// A single adjustment never changes the target by more than this factor
+pub const MAX_ADJUSTMENT_FACTOR: u128 = 4;
+
+/// Scale `target` by how long the last window actually took compared to how long it should
+/// have taken. A slow window raises the target (easier), a fast one lowers it (harder).
+pub fn retarget(target: &H256, actual_timespan: u128, expected_timespan: u128) -> H256 {
+    let actual_timespan = actual_timespan.clamp(
+        expected_timespan / MAX_ADJUSTMENT_FACTOR,
+        expected_timespan * MAX_ADJUSTMENT_FACTOR,
+    );
+    let target = BigUint::from_bytes_be(target.as_ref());
+    let new_target = target * actual_timespan / expected_timespan;
+    to_h256(new_target)
+}
+
+/// Convert a number back to a 32-byte target, saturating at the easiest possible target
+fn to_h256(value: BigUint) -> H256 {
+    let bytes = value.to_bytes_be();
+    if bytes.len() > 32 {
+        return [0xff; 32].into();
+    }
+    let mut buffer = [0u8; 32];
+    buffer[32 - bytes.len()..].copy_from_slice(&bytes);
+    if buffer == [0u8; 32] {
+        // a zero target could never be met
+        buffer[31] = 1;
+    }
+    buffer.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(leading: u8) -> H256 {
+        let mut bytes = [0u8; 32];
+        bytes[1] = leading;
+        bytes.into()
+    }
+
+    #[test]
+    fn on_schedule_keeps_target() {
+        assert_eq!(retarget(&target(0x10), 1000, 1000), target(0x10));
+    }
+
+    #[test]
+    fn slow_blocks_ease_target() {
+        assert_eq!(retarget(&target(0x10), 2000, 1000), target(0x20));
+    }
+
+    #[test]
+    fn fast_blocks_harden_target() {
+        assert_eq!(retarget(&target(0x10), 500, 1000), target(0x08));
+    }
+
+    #[test]
+    fn adjustment_is_clamped() {
+        assert_eq!(retarget(&target(0x10), 1_000_000, 1000), target(0x40));
+        assert_eq!(retarget(&target(0x10), 0, 1000), target(0x04));
+        assert_eq!(retarget(&[0xff; 32].into(), 2000, 1000), [0xff; 32].into());
+    }
+}
@@ -0,0 +1,133 @@
+use crate::types::block::Header;
+use crate::types::hash::{H256, Hashable};
+use num_bigint::BigUint;
+use std::collections::HashMap;
+
+use super::block_work;
+
+/// A header we know of, whether or not we have its block
+#[derive(Debug, Clone)]
+pub struct HeaderEntry {
+    pub header: Header,
+    pub height: u64,
+    /// Total work of the chain ending in this header
+    pub work: BigUint,
+}
+
+/// Every header that connects to the genesis block, including those whose blocks we have not
+/// downloaded yet. During headers-first sync it runs ahead of the blocks and tells us which
+/// blocks to fetch.
+#[derive(Debug)]
+pub struct HeaderTree {
+    entries: HashMap<H256, HeaderEntry>,
+    /// Header with the most accumulated work
+    best: H256,
+}
+
+impl HeaderTree {
+    pub fn new(genesis: &Header) -> Self {
+        let hash = genesis.hash();
+        let entry = HeaderEntry {
+            header: genesis.clone(),
+            height: 0,
+            work: block_work(&genesis.difficulty),
+        };
+        Self { entries: HashMap::from([(hash, entry)]), best: hash }
+    }
+
+    /// Add a header whose parent is already in the tree. Returns false if the parent is unknown.
+    pub fn insert(&mut self, header: &Header) -> bool {
+        let hash = header.hash();
+        if self.entries.contains_key(&hash) {
+            return true;
+        }
+        let parent = match self.entries.get(&header.parent) {
+            Some(parent) => parent,
+            None => return false,
+        };
+        let entry = HeaderEntry {
+            header: header.clone(),
+            height: parent.height + 1,
+            work: &parent.work + block_work(&header.difficulty),
+        };
+        // the same rule as for the longest chain: most work, then the lower hash
+        let best_work = &self.entries[&self.best].work;
+        if entry.work > *best_work || (entry.work == *best_work && hash < self.best) {
+            self.best = hash;
+        }
+        self.entries.insert(hash, entry);
+        true
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&HeaderEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// The header with the most accumulated work
+    pub fn best(&self) -> H256 {
+        self.best
+    }
+
+    pub fn best_height(&self) -> u64 {
+        self.entries[&self.best].height
+    }
+
+    /// A block locator for the best header chain: the ten most recent hashes, then hashes
+    /// exponentially further apart, always ending with the genesis block. A peer finds the
+    /// last block we share with it in `O(log n)` hashes even if we are on a fork.
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator = vec![];
+        let mut hash = self.best;
+        let mut step = 1;
+        loop {
+            locator.push(hash);
+            let mut entry = &self.entries[&hash];
+            if entry.height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            // step back, but never past the genesis block
+            for _ in 0..step {
+                if entry.height == 0 {
+                    break;
+                }
+                hash = entry.header.parent;
+                entry = &self.entries[&hash];
+            }
+        }
+        locator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn locator_thins_out_towards_genesis() {
+        let genesis = generate_random_block(&H256::default()).header;
+        let mut tree = HeaderTree::new(&genesis);
+        let mut chain = vec![genesis.hash()];
+        for _ in 0..30 {
+            let header = generate_random_block(chain.last().unwrap()).header;
+            assert!(tree.insert(&header));
+            chain.push(header.hash());
+        }
+        assert_eq!(tree.best(), chain[30]);
+        assert_eq!(tree.best_height(), 30);
+
+        let heights: Vec<u64> = tree.locator().iter().map(|hash| tree.get(hash).unwrap().height).collect();
+        assert_eq!(heights, vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]);
+
+        // a header that does not connect is not added
+        let orphan = generate_random_block(&H256::default()).header;
+        assert!(!tree.insert(&generate_random_block(&orphan.hash()).header));
+    }
+}
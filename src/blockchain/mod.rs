@@ -1,21 +1,82 @@
+pub mod difficulty;
+pub mod headers;
+pub mod reward;
+pub mod store;
+pub mod validation;
+
 use crate::types::block::{Block, Header, Content};
 use crate::types::hash::H256;
 use crate::types::hash::Hashable;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::info;
+use num_bigint::BigUint;
 use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use difficulty::{RETARGET_INTERVAL, TARGET_BLOCK_TIME};
+use headers::HeaderTree;
+use reward::RewardSchedule;
+use store::{BlockStore, FileStore, MemoryStore};
 
 //pub static DIFFICULTY: [u8; 32] = [0, 0, 30, 50, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10];
 //pub static DIFFICULTY: [u8; 32] = [0, 3, 10, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1];
+/// Target of the genesis block, from which every later target is retargeted
 pub static DIFFICULTY: [u8; 32] = [0, 1, 150, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1];
 
 pub struct Blockchain {
     pub blocks: HashMap<H256, Block>,
     pub tip: H256,  // The hash of the block at the tip of the longest chain
+    genesis_hash: H256,  // The hash of the genesis block, which every node of the network must share
     pub heights: HashMap<H256, u64>,  // A map from block hash to block height
+    pub chain_work: HashMap<H256, BigUint>,  // A map from block hash to the total work of the chain ending in it
+    pub reward_schedule: RewardSchedule,  // How much a block's coinbase may mint
+    pub headers: HeaderTree,  // Every known header, including those of blocks not downloaded yet
+    store: Box<dyn BlockStore>,  // Where inserted blocks are persisted
+    reorg_subscribers: Vec<Sender<Reorg>>,  // Channels notified whenever the longest chain switches branches
+}
+
+/// A switch of the longest chain from one branch to another
+#[derive(Debug, Clone)]
+pub struct Reorg {
+    pub old_tip: H256,
+    pub new_tip: H256,
+    /// The last block shared by the old and the new branch
+    pub common_ancestor: H256,
+    /// Blocks no longer on the longest chain, from the old tip back to the common ancestor
+    pub disconnected: Vec<Block>,
+    /// Blocks newly on the longest chain, from the common ancestor up to the new tip
+    pub connected: Vec<Block>,
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Self::genesis(Box::new(MemoryStore))
+    }
+
+    /// Open a blockchain persisted under `data_dir`, replaying every stored block
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        Self::with_store(Box::new(FileStore::open(data_dir)?))
+    }
+
+    /// Create a blockchain on top of the given store, replaying the blocks already in it
+    pub fn with_store(mut store: Box<dyn BlockStore>) -> io::Result<Self> {
+        let blocks = store.load()?;
+        let mut blockchain = Self::genesis(store);
+        for block in blocks.iter() {
+            blockchain.connect(block);
+        }
+        info!(
+            "Replayed {} stored blocks, tip: {:?}, height: {}",
+            blocks.len(),
+            blockchain.tip,
+            blockchain.heights[&blockchain.tip]
+        );
+        Ok(blockchain)
+    }
+
+    /// Create a blockchain containing only the genesis block, backed by `store`
+    fn genesis(store: Box<dyn BlockStore>) -> Self {
         // Set fixed values for the genesis block header
         let genesis_header = Header {
             parent: H256::from([0x00; 32]),  // No parent for the genesis block, so all zeros
@@ -42,24 +103,44 @@ impl Blockchain {
         // Initialize the blockchain with the genesis block
         let mut blocks = HashMap::new();
         let mut heights = HashMap::new();
+        let mut chain_work = HashMap::new();
 
+        chain_work.insert(genesis_hash, block_work(&genesis_block.get_difficulty()));
+        let headers = HeaderTree::new(&genesis_block.header);
         blocks.insert(genesis_hash, genesis_block);
         heights.insert(genesis_hash, 0);  // Genesis block has height 0
 
         Self {
             blocks,
             tip: genesis_hash,  // The tip is the genesis block initially
+            genesis_hash,
             heights,  // Track the height of the genesis block
+            chain_work,
+            reward_schedule: RewardSchedule::default(),
+            headers,
+            store,
+            reorg_subscribers: vec![],
+        }
+    }
+
+    /// Insert a block into blockchain, persisting it to the store first.
+    /// The block is not inserted if it cannot be persisted.
+    pub fn insert(&mut self, block: &Block) -> io::Result<()> {
+        if self.blocks.contains_key(&block.hash()) {
+            return Ok(());
         }
+        self.store.append(block)?;
+        self.connect(block);
+        Ok(())
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
+    /// Add a block to the in-memory index and update the tip
+    fn connect(&mut self, block: &Block) {
         let block_hash = block.hash();
         let parent_hash = block.get_parent();
 
-        println!(
-            "Blockchain - Inserting block: {:?}, parent: {:?}, transactions: {:?}",
+        info!(
+            "Inserting block: {:?}, parent: {:?}, transactions: {:?}",
             block_hash,
             parent_hash,
             block.content.transactions.len()
@@ -69,15 +150,138 @@ impl Blockchain {
         let parent_height = self.heights.get(&parent_hash).copied().unwrap_or(0);
         let new_block_height = parent_height + 1;
 
+        // The chain ending in the new block carries its parent's work plus its own
+        let parent_work = self.chain_work.get(&parent_hash).cloned().unwrap_or_default();
+        let new_chain_work = parent_work + block_work(&block.get_difficulty());
+
         // Insert the new block into the blockchain
+        self.headers.insert(&block.header);
         self.blocks.insert(block_hash, block.clone());
         self.heights.insert(block_hash, new_block_height);
 
-        // Update the tip only if the new block's height is greater than the current tip's height
-        let current_tip_height = self.heights[&self.tip];
-        if new_block_height > current_tip_height {
+        // Update the tip if the new block's chain has the most accumulated work.
+        // On a tie, the lower block hash wins so every node picks the same tip.
+        let current_tip_work = &self.chain_work[&self.tip];
+        let old_tip = self.tip;
+        if new_chain_work > *current_tip_work
+            || (new_chain_work == *current_tip_work && block_hash < self.tip)
+        {
             self.tip = block_hash;
         }
+        self.chain_work.insert(block_hash, new_chain_work);
+
+        // Extending the old tip is not a reorg; switching to another branch is
+        if self.tip != old_tip && parent_hash != old_tip {
+            if let Some(reorg) = self.reorg_path(old_tip, self.tip) {
+                info!(
+                    "Reorg from {:?} to {:?}: {} blocks disconnected, {} connected",
+                    reorg.old_tip,
+                    reorg.new_tip,
+                    reorg.disconnected.len(),
+                    reorg.connected.len()
+                );
+                self.reorg_subscribers.retain(|subscriber| subscriber.send(reorg.clone()).is_ok());
+            }
+        }
+    }
+
+    /// Find the common ancestor of two tips and the blocks between it and each of them.
+    /// Returns `None` if either branch does not lead back to the genesis block.
+    fn reorg_path(&self, old_tip: H256, new_tip: H256) -> Option<Reorg> {
+        let mut old = old_tip;
+        let mut new = new_tip;
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        while old != new {
+            // step back on whichever branch is higher (both when they are level)
+            let old_height = *self.heights.get(&old)?;
+            let new_height = *self.heights.get(&new)?;
+            if old_height >= new_height {
+                let block = self.blocks.get(&old)?;
+                old = block.get_parent();
+                disconnected.push(block.clone());
+            }
+            if new_height >= old_height {
+                let block = self.blocks.get(&new)?;
+                new = block.get_parent();
+                connected.push(block.clone());
+            }
+        }
+        connected.reverse();
+        Some(Reorg {
+            old_tip,
+            new_tip,
+            common_ancestor: old,
+            disconnected,
+            connected,
+        })
+    }
+
+    /// Get a channel that receives every future reorg of the longest chain
+    pub fn subscribe(&mut self) -> Receiver<Reorg> {
+        let (sender, receiver) = unbounded();
+        self.reorg_subscribers.push(sender);
+        receiver
+    }
+
+    /// Get the difficulty target a child of `parent` must carry in its header.
+    /// The target only changes every `RETARGET_INTERVAL` blocks, based on how long the
+    /// previous window of blocks took according to their timestamps. Only headers are
+    /// needed, so `parent` may be a header whose block is not downloaded yet.
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let parent_entry = self.headers.get(parent).expect("parent header is known");
+        let parent_height = parent_entry.height;
+        if !(parent_height + 1).is_multiple_of(RETARGET_INTERVAL) {
+            return parent_entry.header.difficulty;
+        }
+
+        // walk back to the first block of the window; the genesis timestamp is fixed
+        // rather than a real mining time, so a window starting there begins at block 1
+        let first_height = std::cmp::max(parent_height.saturating_sub(RETARGET_INTERVAL), 1);
+        let mut first = parent_entry;
+        while first.height > first_height {
+            first = self.headers.get(&first.header.parent).expect("ancestor header is known");
+        }
+        let actual_timespan = parent_entry.header.timestamp.saturating_sub(first.header.timestamp);
+        let expected_timespan = TARGET_BLOCK_TIME * (parent_height - first_height) as u128;
+        difficulty::retarget(&parent_entry.header.difficulty, actual_timespan, expected_timespan)
+    }
+
+    /// Up to `max` headers of the longest chain that follow the first hash of `locator` on it,
+    /// or that follow the genesis block if the locator shares nothing with the longest chain
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let chain = self.all_blocks_in_longest_chain();
+        let positions: HashMap<&H256, usize> = chain.iter().enumerate().map(|(i, hash)| (hash, i)).collect();
+        let start = locator.iter().find_map(|hash| positions.get(hash)).copied().unwrap_or(0);
+        chain[start + 1..]
+            .iter()
+            .take(max)
+            .map(|hash| self.blocks[hash].header.clone())
+            .collect()
+    }
+
+    /// Hashes of up to `max` blocks on the best header chain that we do not have yet,
+    /// lowest first, so that they can be downloaded
+    pub fn missing_blocks(&self, max: usize) -> Vec<H256> {
+        let mut missing = vec![];
+        let mut hash = self.headers.best();
+        while !self.blocks.contains_key(&hash) {
+            missing.push(hash);
+            hash = self.headers.get(&hash).expect("best header chain is connected").header.parent;
+        }
+        missing.reverse();
+        missing.truncate(max);
+        missing
+    }
+
+    /// Get the hash of the genesis block
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis_hash
+    }
+
+    /// Get the height of the tip of the longest chain
+    pub fn tip_height(&self) -> u64 {
+        self.heights[&self.tip]
     }
 
     /// Get the last block's hash of the longest chain
@@ -101,6 +305,13 @@ impl Blockchain {
     }
 }
 
+/// The expected number of hashes needed to find a block meeting `difficulty`,
+/// i.e. 2^256 / (target + 1)
+pub fn block_work(difficulty: &H256) -> BigUint {
+    let target = BigUint::from_bytes_be(difficulty.as_ref());
+    (BigUint::from(1u8) << 256) / (target + 1u8)
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -114,9 +325,190 @@ mod tests {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.tip(), block.hash());
+
+    }
+
+    #[test]
+    fn most_work_beats_most_blocks() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let easy: H256 = [0xff; 32].into();
+        let hard: H256 = [0x00, 0x00, 0x0f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff].into();
+
+        // three easy blocks
+        let mut previous_hash = genesis_hash;
+        for _ in 0..3 {
+            let mut block = generate_random_block(&previous_hash);
+            block.header.difficulty = easy;
+            blockchain.insert(&block).unwrap();
+            previous_hash = block.hash();
+        }
+        assert_eq!(blockchain.tip(), previous_hash);
+
+        // one hard block on a fork outweighs them
+        let mut block = generate_random_block(&genesis_hash);
+        block.header.difficulty = hard;
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 2);
+    }
+
+    #[test]
+    fn equal_work_tie_breaks_on_hash() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut first = generate_random_block(&genesis_hash);
+        first.header.difficulty = DIFFICULTY.into();
+        let mut second = generate_random_block(&genesis_hash);
+        second.header.difficulty = DIFFICULTY.into();
+        blockchain.insert(&first).unwrap();
+        blockchain.insert(&second).unwrap();
+        assert_eq!(blockchain.tip(), std::cmp::min(first.hash(), second.hash()));
+    }
+
+    #[test]
+    fn difficulty_retargets_on_slow_window() {
+        let mut blockchain = Blockchain::new();
+        let mut previous_hash = blockchain.tip();
+        // blocks 1..RETARGET_INTERVAL, each twice as slow as the target block time
+        for height in 1..RETARGET_INTERVAL {
+            assert_eq!(blockchain.next_difficulty(&previous_hash), DIFFICULTY.into());
+            let mut block = generate_random_block(&previous_hash);
+            block.header.difficulty = DIFFICULTY.into();
+            block.header.timestamp = height as u128 * 2 * TARGET_BLOCK_TIME;
+            blockchain.insert(&block).unwrap();
+            previous_hash = block.hash();
+        }
+        let expected = difficulty::retarget(&DIFFICULTY.into(), 2, 1);
+        assert_eq!(blockchain.next_difficulty(&previous_hash), expected);
+        assert!(expected > DIFFICULTY.into());
+    }
+
+    #[test]
+    fn reorg_is_emitted() {
+        let mut blockchain = Blockchain::new();
+        let reorgs = blockchain.subscribe();
+        let genesis_hash = blockchain.tip();
+        // work 1 and work 2 per block
+        let easy: H256 = [0xff; 32].into();
+        let mut twice: [u8; 32] = [0xff; 32];
+        twice[0] = 0x7f;
+        let twice: H256 = twice.into();
 
+        let mut main_chain = vec![];
+        let mut previous_hash = genesis_hash;
+        for difficulty in [easy, twice] {
+            let mut block = generate_random_block(&previous_hash);
+            block.header.difficulty = difficulty;
+            blockchain.insert(&block).unwrap();
+            previous_hash = block.hash();
+            main_chain.push(previous_hash);
+        }
+        // extending the tip is not a reorg
+        assert!(reorgs.try_recv().is_err());
+
+        // the fork only overtakes the main chain with its second block
+        let mut fork = vec![];
+        let mut previous_hash = main_chain[0];
+        for difficulty in [easy, twice] {
+            let mut block = generate_random_block(&previous_hash);
+            block.header.difficulty = difficulty;
+            blockchain.insert(&block).unwrap();
+            previous_hash = block.hash();
+            fork.push(previous_hash);
+        }
+        assert_eq!(blockchain.tip(), fork[1]);
+
+        let reorg = reorgs.try_recv().unwrap();
+        assert_eq!(reorg.old_tip, main_chain[1]);
+        assert_eq!(reorg.new_tip, fork[1]);
+        assert_eq!(reorg.common_ancestor, main_chain[0]);
+        let disconnected: Vec<H256> = reorg.disconnected.iter().map(|b| b.hash()).collect();
+        let connected: Vec<H256> = reorg.connected.iter().map(|b| b.hash()).collect();
+        assert_eq!(disconnected, vec![main_chain[1]]);
+        assert_eq!(connected, fork);
+        assert!(reorgs.try_recv().is_err());
+    }
+
+    #[test]
+    fn headers_lead_block_download() {
+        let mut source = Blockchain::new();
+        let mut chain = vec![source.tip()];
+        for _ in 0..5 {
+            let block = generate_random_block(chain.last().unwrap());
+            source.insert(&block).unwrap();
+            chain.push(block.hash());
+        }
+        let hashes = |headers: &[Header]| headers.iter().map(|header| header.hash()).collect::<Vec<H256>>();
+
+        let mut blockchain = Blockchain::new();
+        let headers = source.headers_after(&blockchain.headers.locator(), 3);
+        assert_eq!(hashes(&headers), chain[1..4]);
+        for header in &headers {
+            assert!(blockchain.headers.insert(header));
+        }
+        // the locator now starts at the third block, so the rest follows it
+        let rest = source.headers_after(&blockchain.headers.locator(), 10);
+        assert_eq!(hashes(&rest), chain[4..]);
+        for header in &rest {
+            assert!(blockchain.headers.insert(header));
+        }
+        assert_eq!(blockchain.headers.best_height(), 5);
+        assert_eq!(blockchain.tip(), chain[0]);
+
+        assert_eq!(blockchain.missing_blocks(2), chain[1..3]);
+        blockchain.insert(&source.blocks[&chain[1]]).unwrap();
+        assert_eq!(blockchain.missing_blocks(10), chain[2..]);
+        assert_eq!(blockchain.tip(), chain[1]);
+    }
+
+    #[test]
+    fn reopen_from_data_dir() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-chain-{}", crate::types::hash::generate_random_hash()));
+        let (tip, heights) = {
+            let mut blockchain = Blockchain::open(&dir).unwrap();
+            let genesis_hash = blockchain.tip();
+            let mut previous_hash = genesis_hash;
+            for _ in 0..5 {
+                let block = generate_random_block(&previous_hash);
+                blockchain.insert(&block).unwrap();
+                previous_hash = block.hash();
+            }
+            // a fork off the genesis block must be replayed as well
+            let mut fork = generate_random_block(&genesis_hash);
+            fork.header.difficulty = [0xff; 32].into();
+            blockchain.insert(&fork).unwrap();
+            (blockchain.tip(), blockchain.heights.clone())
+        };
+
+        let blockchain = Blockchain::open(&dir).unwrap();
+        assert_eq!(blockchain.tip(), tip);
+        assert_eq!(blockchain.heights, heights);
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpersisted_block_is_not_inserted() {
+        struct FailingStore;
+        impl BlockStore for FailingStore {
+            fn append(&mut self, _block: &Block) -> io::Result<()> {
+                Err(io::Error::other("disk full"))
+            }
+            fn load(&mut self) -> io::Result<Vec<Block>> {
+                Ok(vec![])
+            }
+        }
+
+        let mut blockchain = Blockchain::with_store(Box::new(FailingStore)).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        assert!(blockchain.insert(&block).is_err());
+        assert_eq!(blockchain.tip(), genesis_hash);
+        assert!(!blockchain.blocks.contains_key(&block.hash()));
     }
 
     /*
@@ -129,7 +521,7 @@ mod tests {
         let mut previous_hash = genesis_hash;
         for _ in 0..25 {
             let new_block = generate_random_block(&previous_hash);
-            blockchain.insert(&new_block);
+            blockchain.insert(&new_block).unwrap();
             previous_hash = new_block.hash();
         }
 
@@ -142,7 +534,7 @@ mod tests {
         let mut fork_hash_1 = fork_point_1;
         for _ in 0..10 {
             let fork_block = generate_random_block(&fork_hash_1);
-            blockchain.insert(&fork_block);
+            blockchain.insert(&fork_block).unwrap();
             fork_hash_1 = fork_block.hash();
         }
 
@@ -154,7 +546,7 @@ mod tests {
         let mut fork_hash_2 = fork_point_2;
         for _ in 0..15 {
             let fork_block = generate_random_block(&fork_hash_2);
-            blockchain.insert(&fork_block);
+            blockchain.insert(&fork_block).unwrap();
             fork_hash_2 = fork_block.hash();
         }
 
@@ -166,7 +558,7 @@ mod tests {
         let mut fork_hash_3 = fork_point_3;
         for _ in 0..20 {
             let fork_block = generate_random_block(&fork_hash_3);
-            blockchain.insert(&fork_block);
+            blockchain.insert(&fork_block).unwrap();
             fork_hash_3 = fork_block.hash();
         }
 
@@ -177,7 +569,7 @@ mod tests {
         // Now, continue adding to Fork 3 to make it the longest chain:
         for _ in 0..3 {
             let fork_block = generate_random_block(&fork_hash_3);
-            blockchain.insert(&fork_block);
+            blockchain.insert(&fork_block).unwrap();
             fork_hash_3 = fork_block.hash();
         }
 
@@ -203,7 +595,7 @@ mod tests {
         let mut previous_hash = genesis_hash;
         for _ in 0..50 {
             let block = generate_random_block(&previous_hash);
-            blockchain.insert(&block);
+            blockchain.insert(&block).unwrap();
             previous_hash = block.hash();
         }
 
@@ -225,7 +617,7 @@ mod tests {
         let mut previous_hash = genesis_hash;
         for _ in 0..5 {
             let block = generate_random_block(&previous_hash);
-            blockchain.insert(&block);
+            blockchain.insert(&block).unwrap();
             previous_hash = block.hash();
         }
 
@@ -234,14 +626,14 @@ mod tests {
         let mut fork_hash = fork_block_hash;
         for _ in 0..3 {
             let fork_block = generate_random_block(&fork_hash);
-            blockchain.insert(&fork_block);
+            blockchain.insert(&fork_block).unwrap();
             fork_hash = fork_block.hash();
         }
 
         // Add 2 more blocks to the main chain (making it longer than the fork)
         for _ in 0..2 {
             let block = generate_random_block(&previous_hash);
-            blockchain.insert(&block);
+            blockchain.insert(&block).unwrap();
             previous_hash = block.hash();
         }
 
@@ -263,7 +655,7 @@ mod tests {
         let mut inserted_hashes = vec![genesis_hash];
         for _ in 0..10 {
             let block = generate_random_block(&previous_hash);
-            blockchain.insert(&block);
+            blockchain.insert(&block).unwrap();
             previous_hash = block.hash();
             inserted_hashes.push(previous_hash);
         }
@@ -282,7 +674,7 @@ mod tests {
         let mut previous_hash = genesis_hash;
         for _ in 0..5 {
             let block = generate_random_block(&previous_hash);
-            blockchain.insert(&block);
+            blockchain.insert(&block).unwrap();
             previous_hash = block.hash();
         }
 
@@ -291,7 +683,7 @@ mod tests {
         let mut fork_hash = fork_block_hash;
         for _ in 0..2 {
             let fork_block = generate_random_block(&fork_hash);
-            blockchain.insert(&fork_block);
+            blockchain.insert(&fork_block).unwrap();
             fork_hash = fork_block.hash();
         }
 
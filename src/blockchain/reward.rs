@@ -0,0 +1,52 @@
+/// How many coins a block may mint, halving every `halving_interval` blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RewardSchedule {
+    /// Subsidy of the blocks before the first halving
+    pub initial_subsidy: u64,
+    /// Number of blocks between two halvings (0 never halves)
+    pub halving_interval: u64,
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        Self {
+            initial_subsidy: 50,
+            halving_interval: 1000,
+        }
+    }
+}
+
+impl RewardSchedule {
+    /// The subsidy a block at `height` may mint
+    pub fn subsidy(&self, height: u64) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_subsidy;
+        }
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_subsidy >> halvings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsidy_halves() {
+        let schedule = RewardSchedule { initial_subsidy: 100, halving_interval: 10 };
+        assert_eq!(schedule.subsidy(1), 100);
+        assert_eq!(schedule.subsidy(9), 100);
+        assert_eq!(schedule.subsidy(10), 50);
+        assert_eq!(schedule.subsidy(25), 25);
+        assert_eq!(schedule.subsidy(10 * 40), 0);
+    }
+
+    #[test]
+    fn zero_interval_never_halves() {
+        let schedule = RewardSchedule { initial_subsidy: 100, halving_interval: 0 };
+        assert_eq!(schedule.subsidy(1_000_000), 100);
+    }
+}
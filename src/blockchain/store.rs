@@ -0,0 +1,297 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Magic bytes at the start of the block log
+const LOG_MAGIC: &[u8; 4] = b"BLKS";
+/// Version of the on-disk record format, bumped whenever `Block` serialization changes.
+/// Version 2 added transaction fees, version 3 widened transaction values and fees to `u64`.
+pub const STORE_FORMAT_VERSION: u32 = 3;
+/// Size of the block log header (magic + format version)
+const LOG_HEADER_LEN: u64 = 8;
+/// Size of one index entry (block hash + record offset + record length)
+const INDEX_ENTRY_LEN: usize = 32 + 8 + 4;
+
+/// A decoded log record: the block, its offset in the log and its payload length
+type Record = (Block, u64, u32);
+
+/// A place where the blockchain keeps its blocks across restarts.
+pub trait BlockStore: Send {
+    /// Durably record a block. Returns only once the block is on disk.
+    fn append(&mut self, block: &Block) -> io::Result<()>;
+
+    /// Read back every stored block, in the order they were appended.
+    fn load(&mut self) -> io::Result<Vec<Block>>;
+}
+
+/// A store that keeps nothing, for nodes started without a data directory
+pub struct MemoryStore;
+
+impl BlockStore for MemoryStore {
+    fn append(&mut self, _block: &Block) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Block>> {
+        Ok(vec![])
+    }
+}
+
+/// An append-only block log plus an index file.
+///
+/// `blocks.log` holds a small header followed by records of the form
+/// `[u32 big-endian length][bincode block]`. `blocks.idx` holds one fixed-size
+/// entry `[block hash][u64 offset][u32 length]` per record in the log. The log
+/// is the source of truth: on open, a torn trailing record left by a crash is
+/// truncated away, and the index is rebuilt if it does not match the log.
+pub struct FileStore {
+    log: File,
+    index: File,
+    /// Offset in the log where the next record will be written
+    log_end: u64,
+}
+
+impl FileStore {
+    /// Open (or create) the block store inside `dir`
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join("blocks.log"))?;
+        let index = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join("blocks.idx"))?;
+
+        if log.metadata()?.len() < LOG_HEADER_LEN {
+            // fresh (or torn before the header was complete) log
+            log.set_len(0)?;
+            log.seek(SeekFrom::Start(0))?;
+            log.write_all(LOG_MAGIC)?;
+            log.write_all(&STORE_FORMAT_VERSION.to_be_bytes())?;
+            log.sync_all()?;
+        } else {
+            let mut header = [0u8; LOG_HEADER_LEN as usize];
+            log.seek(SeekFrom::Start(0))?;
+            log.read_exact(&mut header)?;
+            let version = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            if &header[0..4] != LOG_MAGIC || version != STORE_FORMAT_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported block log format (version {})", version),
+                ));
+            }
+        }
+
+        let log_end = log.metadata()?.len();
+        Ok(Self { log, index, log_end })
+    }
+
+    /// Scan the log from the header on, returning every complete record and its position.
+    /// Stops at the first torn or undecodable record.
+    fn scan_log(&mut self) -> io::Result<(Vec<Record>, u64)> {
+        let mut bytes = vec![];
+        self.log.seek(SeekFrom::Start(0))?;
+        self.log.read_to_end(&mut bytes)?;
+
+        let mut records = vec![];
+        let mut pos = LOG_HEADER_LEN as usize;
+        while pos + 4 <= bytes.len() {
+            let len = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]);
+            let start = pos + 4;
+            let end = start + len as usize;
+            if end > bytes.len() {
+                break;
+            }
+            match bincode::deserialize::<Block>(&bytes[start..end]) {
+                Ok(block) => records.push((block, pos as u64, len)),
+                Err(_) => break,
+            }
+            pos = end;
+        }
+        Ok((records, pos as u64))
+    }
+
+    /// Read the index file, ignoring a torn trailing entry
+    fn read_index(&mut self) -> io::Result<Vec<(H256, u64, u32)>> {
+        let mut bytes = vec![];
+        self.index.seek(SeekFrom::Start(0))?;
+        self.index.read_to_end(&mut bytes)?;
+        Ok(bytes
+            .chunks_exact(INDEX_ENTRY_LEN)
+            .map(|entry| {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(&entry[0..32]);
+                let mut offset = [0u8; 8];
+                offset.copy_from_slice(&entry[32..40]);
+                let mut len = [0u8; 4];
+                len.copy_from_slice(&entry[40..44]);
+                (hash.into(), u64::from_be_bytes(offset), u32::from_be_bytes(len))
+            })
+            .collect())
+    }
+
+    fn encode_index_entry(hash: &H256, offset: u64, len: u32) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(INDEX_ENTRY_LEN);
+        entry.extend_from_slice(hash.as_ref());
+        entry.extend_from_slice(&offset.to_be_bytes());
+        entry.extend_from_slice(&len.to_be_bytes());
+        entry
+    }
+}
+
+impl BlockStore for FileStore {
+    fn append(&mut self, block: &Block) -> io::Result<()> {
+        let payload = bincode::serialize(block)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let len = payload.len() as u32;
+        let offset = self.log_end;
+
+        // the log is written and synced first, so the index never points past the log
+        let mut record = Vec::with_capacity(4 + payload.len());
+        record.extend_from_slice(&len.to_be_bytes());
+        record.extend_from_slice(&payload);
+        self.log.seek(SeekFrom::Start(offset))?;
+        self.log.write_all(&record)?;
+        self.log.sync_data()?;
+        self.log_end = offset + record.len() as u64;
+
+        let hash = block.hash();
+        self.index.seek(SeekFrom::End(0))?;
+        self.index.write_all(&Self::encode_index_entry(&hash, offset, len))?;
+        self.index.sync_data()?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Block>> {
+        let (records, good_end) = self.scan_log()?;
+        let file_len = self.log.metadata()?.len();
+        if good_end < file_len {
+            warn!(
+                "Block log has {} trailing bytes that do not form a complete record, truncating",
+                file_len - good_end
+            );
+            self.log.set_len(good_end)?;
+            self.log.sync_all()?;
+        }
+        self.log_end = good_end;
+
+        let expected: Vec<(H256, u64, u32)> = records
+            .iter()
+            .map(|(block, offset, len)| (block.hash(), *offset, *len))
+            .collect();
+        if self.read_index()? != expected {
+            info!("Block index does not match block log, rebuilding it");
+            let mut entries = Vec::with_capacity(expected.len() * INDEX_ENTRY_LEN);
+            for (hash, offset, len) in expected.iter() {
+                entries.extend_from_slice(&Self::encode_index_entry(hash, *offset, *len));
+            }
+            self.index.set_len(0)?;
+            self.index.seek(SeekFrom::Start(0))?;
+            self.index.write_all(&entries)?;
+            self.index.sync_all()?;
+        }
+        Ok(records.into_iter().map(|(block, _, _)| block).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+    use std::path::PathBuf;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bitcoin-store-{}", generate_random_hash()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn append_chain(store: &mut FileStore, length: usize) -> Vec<Block> {
+        let mut blocks = vec![];
+        let mut parent = generate_random_hash();
+        for _ in 0..length {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            store.append(&block).unwrap();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn reopen_returns_appended_blocks() {
+        let dir = temp_dir();
+        let blocks = {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.load().unwrap();
+            append_chain(&mut store, 5)
+        };
+        let mut store = FileStore::open(&dir).unwrap();
+        let loaded = store.load().unwrap();
+        let loaded: Vec<H256> = loaded.iter().map(|b| b.hash()).collect();
+        let expected: Vec<H256> = blocks.iter().map(|b| b.hash()).collect();
+        assert_eq!(loaded, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_trailing_record_is_dropped() {
+        let dir = temp_dir();
+        let blocks = {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.load().unwrap();
+            append_chain(&mut store, 3)
+        };
+        // simulate a crash in the middle of writing the last record
+        let log_path = dir.join("blocks.log");
+        let len = fs::metadata(&log_path).unwrap().len();
+        OpenOptions::new().write(true).open(&log_path).unwrap().set_len(len - 10).unwrap();
+
+        let mut store = FileStore::open(&dir).unwrap();
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].hash(), blocks[1].hash());
+
+        // the store keeps working after recovery
+        let next = generate_random_block(&blocks[1].hash());
+        store.append(&next).unwrap();
+        let mut store = FileStore::open(&dir).unwrap();
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded[2].hash(), next.hash());
+        assert_eq!(
+            fs::metadata(dir.join("blocks.idx")).unwrap().len() as usize,
+            3 * INDEX_ENTRY_LEN
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_index_is_rebuilt() {
+        let dir = temp_dir();
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.load().unwrap();
+            append_chain(&mut store, 4);
+        }
+        fs::remove_file(dir.join("blocks.idx")).unwrap();
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.load().unwrap().len(), 4);
+        assert_eq!(
+            fs::metadata(dir.join("blocks.idx")).unwrap().len() as usize,
+            4 * INDEX_ENTRY_LEN
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+use crate::blockchain::Blockchain;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::merkle::MerkleTree;
+use crate::types::state::StateError;
+use crate::types::transaction::{verify_signed, SignatureError};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far ahead of our own clock a block timestamp may be, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// Why a block was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    /// The parent block is not in our blockchain
+    UnknownParent(H256),
+    /// The header's difficulty is not the one the retargeting rule requires
+    WrongDifficulty { expected: H256, found: H256 },
+    /// The block hash does not meet the header's difficulty
+    InsufficientProofOfWork,
+    /// The timestamp is earlier than the parent's timestamp
+    TimestampBeforeParent { parent: u128, found: u128 },
+    /// The timestamp is too far ahead of our clock
+    TimestampInFuture { now: u128, found: u128 },
+    /// The merkle root does not commit to the block's transactions
+    MerkleRootMismatch { expected: H256, found: H256 },
+    /// A transaction signature does not verify
+    InvalidSignature(H256),
+    /// A transaction is signed by a key that does not own its sender address
+    WrongSender(H256),
+    /// A coinbase transaction appears anywhere but first in the block
+    MisplacedCoinbase(H256),
+    /// The coinbase nonce is not the block height
+    CoinbaseWrongHeight { expected: u64, found: u32 },
+    /// The coinbase pays more than the block subsidy plus the block's fees
+    CoinbaseTooLarge { allowed: u64, found: u64 },
+    /// The fees of the block's transactions, plus the subsidy, do not fit in an amount
+    RewardOverflow,
+    /// The block's transactions cannot be applied to its parent's state
+    InvalidState(StateError),
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::UnknownParent(parent) => write!(f, "unknown parent block {}", parent),
+            BlockError::WrongDifficulty { expected, found } => {
+                write!(f, "difficulty {} does not match expected {}", found, expected)
+            }
+            BlockError::InsufficientProofOfWork => write!(f, "block hash does not meet its difficulty"),
+            BlockError::TimestampBeforeParent { parent, found } => {
+                write!(f, "timestamp {} is before parent timestamp {}", found, parent)
+            }
+            BlockError::TimestampInFuture { now, found } => {
+                write!(f, "timestamp {} is too far ahead of local time {}", found, now)
+            }
+            BlockError::MerkleRootMismatch { expected, found } => {
+                write!(f, "merkle root {} does not match transactions root {}", found, expected)
+            }
+            BlockError::InvalidSignature(tx) => write!(f, "invalid signature on transaction {}", tx),
+            BlockError::WrongSender(tx) => write!(f, "transaction {} is not signed by its sender", tx),
+            BlockError::MisplacedCoinbase(tx) => write!(f, "coinbase transaction {} is not first in the block", tx),
+            BlockError::CoinbaseWrongHeight { expected, found } => {
+                write!(f, "coinbase nonce {} does not match block height {}", found, expected)
+            }
+            BlockError::CoinbaseTooLarge { allowed, found } => {
+                write!(f, "coinbase value {} exceeds allowed reward {}", found, allowed)
+            }
+            BlockError::RewardOverflow => write!(f, "block subsidy plus fees overflows"),
+            BlockError::InvalidState(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+impl From<StateError> for BlockError {
+    fn from(e: StateError) -> Self {
+        BlockError::InvalidState(e)
+    }
+}
+
+impl BlockError {
+    /// Whether the block can never become valid. A block whose parent we have not seen, or
+    /// whose timestamp is only ahead of our own clock, may still be accepted later.
+    pub fn is_permanent(&self) -> bool {
+        !matches!(self, BlockError::UnknownParent(_) | BlockError::TimestampInFuture { .. })
+    }
+}
+
+/// Check that the block hash meets the difficulty claimed in its own header.
+/// This needs no context, so it can be done before the parent is known.
+pub fn check_proof_of_work(header: &Header) -> Result<(), BlockError> {
+    if header.hash() > header.difficulty {
+        return Err(BlockError::InsufficientProofOfWork);
+    }
+    Ok(())
+}
+
+/// Validate a header against its parent in `blockchain`: the parent header must be known, the
+/// difficulty must be the retargeted one, the proof of work must meet it, and the timestamp
+/// must be neither before the parent's nor too far in the future.
+pub fn validate_header(header: &Header, blockchain: &Blockchain) -> Result<(), BlockError> {
+    let parent = match blockchain.headers.get(&header.parent) {
+        Some(parent) => parent,
+        None => return Err(BlockError::UnknownParent(header.parent)),
+    };
+
+    let expected = blockchain.next_difficulty(&header.parent);
+    if header.difficulty != expected {
+        return Err(BlockError::WrongDifficulty { expected, found: header.difficulty });
+    }
+    check_proof_of_work(header)?;
+
+    if header.timestamp < parent.header.timestamp {
+        return Err(BlockError::TimestampBeforeParent {
+            parent: parent.header.timestamp,
+            found: header.timestamp,
+        });
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
+    if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(BlockError::TimestampInFuture { now, found: header.timestamp });
+    }
+    Ok(())
+}
+
+/// Validate the parts of a block that do not depend on the chain: the merkle root
+/// must commit to the transactions and every transaction must be signed by its sender.
+pub fn validate_block_content(block: &Block) -> Result<(), BlockError> {
+    let expected = MerkleTree::new(&block.content.transactions).root();
+    if block.header.merkle_root != expected {
+        return Err(BlockError::MerkleRootMismatch { expected, found: block.header.merkle_root });
+    }
+    for (index, tx) in block.content.transactions.iter().enumerate() {
+        if tx.is_coinbase() {
+            // a coinbase is not signed; its amount is checked by `validate_coinbase`
+            if index != 0 {
+                return Err(BlockError::MisplacedCoinbase(tx.hash()));
+            }
+            continue;
+        }
+        match verify_signed(tx) {
+            Ok(()) => {}
+            Err(SignatureError::WrongSender { .. }) => return Err(BlockError::WrongSender(tx.hash())),
+            Err(SignatureError::InvalidSignature) => return Err(BlockError::InvalidSignature(tx.hash())),
+        }
+    }
+    Ok(())
+}
+
+/// Validate the coinbase of a block whose parent is in `blockchain`, if it has one: its nonce
+/// must be the block height and it may pay at most the subsidy for that height plus the fees
+/// of the block's other transactions.
+pub fn validate_coinbase(block: &Block, blockchain: &Blockchain) -> Result<(), BlockError> {
+    let coinbase = match block.content.transactions.first() {
+        Some(tx) if tx.is_coinbase() => &tx.transaction,
+        _ => return Ok(()),
+    };
+    let height = match blockchain.heights.get(&block.get_parent()) {
+        Some(parent_height) => parent_height + 1,
+        None => return Err(BlockError::UnknownParent(block.get_parent())),
+    };
+    if coinbase.account_nonce as u64 != height {
+        return Err(BlockError::CoinbaseWrongHeight { expected: height, found: coinbase.account_nonce });
+    }
+    let allowed = block.content.transactions[1..]
+        .iter()
+        .try_fold(blockchain.reward_schedule.subsidy(height), |total, tx| total.checked_add(tx.transaction.fee))
+        .ok_or(BlockError::RewardOverflow)?;
+    if coinbase.value > allowed {
+        return Err(BlockError::CoinbaseTooLarge { allowed, found: coinbase.value });
+    }
+    Ok(())
+}
+
+/// Fully validate a block whose parent is in `blockchain`
+pub fn validate_block(block: &Block, blockchain: &Blockchain) -> Result<(), BlockError> {
+    validate_header(&block.header, blockchain)?;
+    validate_block_content(block)?;
+    validate_coinbase(block, blockchain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::block::generate_random_block;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign, signed_transfer, SignedTransaction};
+    use ring::signature::KeyPair;
+
+    /// Re-mine a block after its header was changed by a test
+    fn mine(block: &mut Block) {
+        while check_proof_of_work(&block.header).is_err() {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+    }
+
+    #[test]
+    fn valid_block_passes() {
+        let blockchain = Blockchain::new();
+        let block = generate_random_block(&blockchain.tip());
+        assert_eq!(validate_block(&block, &blockchain), Ok(()));
+    }
+
+    #[test]
+    fn unknown_parent() {
+        let blockchain = Blockchain::new();
+        let block = generate_random_block(&blockchain.tip());
+        let orphan = generate_random_block(&block.hash());
+        assert_eq!(validate_block(&orphan, &blockchain), Err(BlockError::UnknownParent(block.hash())));
+    }
+
+    #[test]
+    fn wrong_difficulty() {
+        let blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.difficulty = [0xff; 32].into();
+        assert!(matches!(
+            validate_block(&block, &blockchain),
+            Err(BlockError::WrongDifficulty { .. })
+        ));
+    }
+
+    #[test]
+    fn insufficient_proof_of_work() {
+        let blockchain = Blockchain::new();
+        let mut block = generate_random_block(&blockchain.tip());
+        while block.hash() <= block.get_difficulty() {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        assert_eq!(validate_block(&block, &blockchain), Err(BlockError::InsufficientProofOfWork));
+    }
+
+    #[test]
+    fn timestamp_rules() {
+        let mut blockchain = Blockchain::new();
+        let parent = generate_random_block(&blockchain.tip());
+        blockchain.insert(&parent).unwrap();
+
+        let mut early = generate_random_block(&parent.hash());
+        early.header.timestamp = parent.header.timestamp - 1;
+        mine(&mut early);
+        assert!(matches!(
+            validate_block(&early, &blockchain),
+            Err(BlockError::TimestampBeforeParent { .. })
+        ));
+
+        let mut late = generate_random_block(&parent.hash());
+        late.header.timestamp += MAX_FUTURE_BLOCK_TIME + 60_000;
+        mine(&mut late);
+        assert!(matches!(
+            validate_block(&late, &blockchain),
+            Err(BlockError::TimestampInFuture { .. })
+        ));
+    }
+
+    #[test]
+    fn merkle_root_and_signatures() {
+        let blockchain = Blockchain::new();
+        let key = key_pair::random();
+        let tx = signed_transfer(&key, 1, 10, 1);
+
+        // transactions the merkle root does not commit to
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.transactions.push(tx.clone());
+        assert!(matches!(
+            validate_block(&block, &blockchain),
+            Err(BlockError::MerkleRootMismatch { .. })
+        ));
+
+        block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+        mine(&mut block);
+        assert_eq!(validate_block(&block, &blockchain), Ok(()));
+
+        let mut forged = tx.clone();
+        forged.transaction.value += 1;
+        block.content.transactions = vec![forged.clone()];
+        block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+        mine(&mut block);
+        assert_eq!(
+            validate_block(&block, &blockchain),
+            Err(BlockError::InvalidSignature(forged.hash()))
+        );
+
+        // a valid signature by a key that does not own the sender, such as a spend from the ICO
+        // account signed with the attacker's own key
+        let attacker = key_pair::random();
+        let mut stolen = tx;
+        stolen.signature = sign(&stolen.transaction, &attacker).as_ref().to_vec();
+        stolen.public_key = attacker.public_key().as_ref().to_vec();
+        block.content.transactions = vec![stolen.clone()];
+        block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+        mine(&mut block);
+        assert_eq!(validate_block(&block, &blockchain), Err(BlockError::WrongSender(stolen.hash())));
+    }
+
+    #[test]
+    fn coinbase_rules() {
+        let blockchain = Blockchain::new();
+        let miner: Address = [7; 20].into();
+        let subsidy = blockchain.reward_schedule.subsidy(1);
+        let with_coinbase = |coinbase: SignedTransaction, first: bool| {
+            let mut block = generate_random_block(&blockchain.tip());
+            block.content.transactions = vec![coinbase];
+            if !first {
+                block.content.transactions.insert(0, SignedTransaction::coinbase(miner, 0, 1));
+            }
+            block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+            mine(&mut block);
+            block
+        };
+
+        let block = with_coinbase(SignedTransaction::coinbase(miner, subsidy, 1), true);
+        assert_eq!(validate_block(&block, &blockchain), Ok(()));
+
+        let coinbase = SignedTransaction::coinbase(miner, subsidy + 1, 1);
+        let block = with_coinbase(coinbase, true);
+        assert_eq!(
+            validate_block(&block, &blockchain),
+            Err(BlockError::CoinbaseTooLarge { allowed: subsidy, found: subsidy + 1 })
+        );
+
+        let block = with_coinbase(SignedTransaction::coinbase(miner, subsidy, 2), true);
+        assert_eq!(
+            validate_block(&block, &blockchain),
+            Err(BlockError::CoinbaseWrongHeight { expected: 1, found: 2 })
+        );
+
+        let second = SignedTransaction::coinbase(miner, subsidy, 1);
+        let block = with_coinbase(second.clone(), false);
+        assert_eq!(validate_block(&block, &blockchain), Err(BlockError::MisplacedCoinbase(second.hash())));
+    }
+}
@@ -10,10 +10,11 @@ use rand::Rng;
 use crate::types::address::Address;
 use crate::blockchain::Blockchain;
 use crate::types::block::BlockState;
+use crate::types::state::Account;
 use crate::types::transaction::{SignedTransaction, Transaction, sign};
 use crate::network::server::Handle as ServerHandle;
 use crate::network::message::Message;
-use crate::miner::Mempool;
+use crate::miner::{admit_transaction, Mempool};
 use crate::types::hash::H256;
 
 
@@ -84,39 +85,40 @@ impl TransactionGenerator {
         }
 
         // Retrieve the state for the sender from the block_state_map
-        let mut sender_state = (0, 0);  // Default sender state (nonce, balance)
+        let mut sender_state = Account::default();
         {
             let block_state_map = self.block_state_map.lock().unwrap();
             if let Some(state) = block_state_map.block_state_map.get(&tip) {
-                if let Some(state_for_sender) = state.get(&self.address) {
-                    sender_state = state_for_sender.clone();
-                }
+                sender_state = state.get(&self.address);
             }
         }
 
         // Debugging output for sender state
-        info!("Sender state for {:?}: nonce = {}, balance = {}", self.address, sender_state.0, sender_state.1);
+        info!("Sender state for {:?}: nonce = {}, balance = {}", self.address, sender_state.nonce, sender_state.balance);
 
         // If the sender balance is 0, we skip transaction generation
-        if sender_state.1 == 0 {
+        if sender_state.balance == 0 {
             info!("Skipping transaction for sender {:?}, balance is 0", self.address);
             continue;
         }
 
         // Generate the transaction value as half the balance or at least 1
-        let mut value = sender_state.1 / 2;
+        let mut value = sender_state.balance / 2;
         if value == 0 {
             value = 1;
         }
 
+        // Pay a fee of 1 whenever the balance left after the value covers it
+        let fee = std::cmp::min(1, sender_state.balance - value);
+
         // Generate the nonce for the transaction (should be the stored sender nonce)
-        let nonce = sender_state.0; // Use the stored nonce for the sender
+        let nonce = sender_state.nonce; // Use the stored nonce for the sender
         let account_nonce = nonce + 1; // The account nonce should always be one higher
 
         // Validate the nonce (should match the sender's current nonce + 1)
-        if account_nonce != sender_state.0 + 1 {
+        if account_nonce != sender_state.nonce + 1 {
             info!("Skipping invalid transaction for sender {:?}, expected nonce: {}, got: {}", 
-                  self.address, sender_state.0 + 1, account_nonce);
+                  self.address, sender_state.nonce + 1, account_nonce);
             continue;  // Skip transaction generation if invalid nonce
         }
 
@@ -129,6 +131,7 @@ impl TransactionGenerator {
             receiver,
             value,
             account_nonce: account_nonce, // Increment the nonce for the transaction
+            fee,
         };
 
         // Sign the transaction
@@ -140,9 +143,10 @@ impl TransactionGenerator {
         };
 
         // Insert the transaction into the mempool
-        {
-            let mut mempool = self.mempool.lock().expect("Failed to lock mempool");
-            mempool.insert(&signed_tx);
+        if let Err(e) = admit_transaction(&signed_tx, &self.blockchain, &self.block_state_map, &self.mempool) {
+            info!("Generated transaction rejected: {}", e);
+            thread::sleep(interval);
+            continue;
         }
 
         // Broadcast the transaction
@@ -10,18 +10,18 @@ pub mod network;
 pub mod generator;
 
 use blockchain::Blockchain;
+use blockchain::reward::RewardSchedule;
 use clap::clap_app;
-use miner::Mempool;
+use miner::{Mempool, MempoolConfig};
 use ring::signature::KeyPair;
 use smol::channel;
-use log::{error, info};
-use api::Server as ApiServer;
+use log::error;
+use api::{ApiContext, Server as ApiServer};
 use types::transaction::ICO;
 use std::net;
+use std::path::Path;
 use std::process;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time;
 
 use crate::types::address::Address;
 use crate::types::block::BlockState;
@@ -36,8 +36,25 @@ fn main() {
         (@arg verbose: -v ... "Increases the verbosity of logging")
         (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
         (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
-        (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+        (@arg known_peer: -c --connect ... [PEER] "Sets the seed peers to connect to at start")
+        (@arg outbound_peers: --("outbound-peers") [INT] default_value("8") "Sets the number of outbound connections to keep by dialing addresses learned from peers")
+        (@arg ban_duration: --("ban-duration") [SECS] default_value("86400") "Sets how long, in seconds, a misbehaving peer stays banned")
+        (@arg max_frame_size: --("max-frame-size") [BYTES] default_value("33554432") "Sets the largest P2P frame a peer may send before it is disconnected")
+        (@arg peer_queue_size: --("peer-queue-size") [INT] default_value("1000") "Sets the number of messages queued for a peer before it counts as slow")
+        (@arg slow_peer: --("slow-peer") [POLICY] default_value("disconnect") possible_value[drop disconnect] "Sets whether messages to a slow peer are dropped or the peer is disconnected")
+        (@arg peer_message_rate: --("peer-message-rate") [INT] default_value("1000") "Sets the messages per second read from each peer (0 for no limit)")
+        (@arg peer_byte_rate: --("peer-byte-rate") [BYTES] default_value("16777216") "Sets the bytes per second read from each peer (0 for no limit)")
+        (@arg encrypt: --encrypt "Encrypts P2P connections and authenticates peers with the node's identity key; all peers have to use it")
         (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+        (@arg data_dir: --("data-dir") [DIR] "Sets the directory where the blockchain is persisted across restarts")
+        (@arg block_subsidy: --("block-subsidy") [INT] default_value("50") "Sets the coins minted by each block before the first halving")
+        (@arg halving_interval: --("halving-interval") [INT] default_value("1000") "Sets the number of blocks between two halvings of the block subsidy")
+        (@arg mempool_max_count: --("mempool-max-count") [INT] default_value("10000") "Sets the number of transactions the mempool holds before evicting the lowest paying")
+        (@arg mempool_max_bytes: --("mempool-max-bytes") [BYTES] default_value("10485760") "Sets the bytes of transactions the mempool holds before evicting the lowest paying")
+        (@arg mempool_sender_limit: --("mempool-sender-limit") [INT] default_value("100") "Sets the number of pending transactions the mempool accepts from a single sender")
+        (@arg mempool_expiry: --("mempool-expiry") [SECS] default_value("10800") "Sets how long, in seconds, a transaction stays in the mempool without being mined")
+        (@arg mempool_replacement_bump: --("mempool-replacement-bump") [PERCENT] default_value("10") "Sets how much higher, in percent, a fee has to be to replace a pending transaction with the same sender and nonce")
+        (@arg snapshot_interval: --("state-snapshot-interval") [INT] default_value("100") "Sets how many blocks apart state snapshots are written to the data directory (0 disables)")
     )
     .get_matches();
 
@@ -46,8 +63,47 @@ fn main() {
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
     // Initialize blockchain and mempool
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let mut blockchain = match matches.value_of("data_dir") {
+        Some(dir) => Blockchain::open(Path::new(dir)).unwrap_or_else(|e| {
+            error!("Error opening blockchain in {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => Blockchain::new(),
+    };
+    blockchain.reward_schedule = RewardSchedule {
+        initial_subsidy: matches
+            .value_of("block_subsidy")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing block subsidy: {}", e);
+                process::exit(1);
+            }),
+        halving_interval: matches
+            .value_of("halving_interval")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing halving interval: {}", e);
+                process::exit(1);
+            }),
+    };
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let parse_mempool_limit = |name: &str, what: &str| {
+        matches.value_of(name).unwrap().parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", what, e);
+            process::exit(1);
+        })
+    };
+    let mempool_config = MempoolConfig {
+        max_count: parse_mempool_limit("mempool_max_count", "mempool max count"),
+        max_bytes: parse_mempool_limit("mempool_max_bytes", "mempool max bytes"),
+        max_per_sender: parse_mempool_limit("mempool_sender_limit", "mempool sender limit"),
+        expiry: std::time::Duration::from_secs(parse_mempool_limit("mempool_expiry", "mempool expiry") as u64),
+        replacement_bump: parse_mempool_limit("mempool_replacement_bump", "mempool replacement bump") as u64,
+        ..MempoolConfig::default()
+    };
+    let mempool = Arc::new(Mutex::new(Mempool::with_config(mempool_config)));
 
     // Create key-pairs for nodes
     let pair0 = Arc::new(given(&[0; 32]));
@@ -59,16 +115,40 @@ fn main() {
     let pair2 = Arc::new(given(&[2; 32]));
     let account2 = Address::from_public_key_bytes(pair2.public_key().as_ref());
 
-    // Initialize state map with ICO initial balances and nonces
-    let mut initial_state = std::collections::HashMap::new();
-    initial_state.insert(account0, (0, 1_000_000));
-    let state_map = Arc::new(Mutex::new(initial_state));
-
     let ico = Arc::new(Mutex::new(ICO::new(pair0.public_key().as_ref())));
 
-    let block_state_map = Arc::new(Mutex::new(BlockState::new()));
-    let genesis_hash = blockchain.lock().unwrap().tip();
-    block_state_map.lock().unwrap().block_state_map.insert(genesis_hash, ico.lock().unwrap().state.clone());
+    let snapshot_interval = matches
+        .value_of("snapshot_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing state snapshot interval: {}", e);
+            process::exit(1);
+        });
+    let block_state = match matches.value_of("data_dir") {
+        // rebuild the state of every stored block from the snapshots on disk
+        Some(dir) => BlockState::open(
+            Path::new(dir).join("state"),
+            snapshot_interval,
+            &blockchain.lock().unwrap(),
+            ico.lock().unwrap().state.clone(),
+        )
+        .unwrap_or_else(|e| {
+            error!("Error opening block states in {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => {
+            let mut block_state = BlockState::new();
+            let genesis_hash = blockchain.lock().unwrap().tip();
+            block_state.block_state_map.insert(genesis_hash, ico.lock().unwrap().state.clone());
+            block_state
+        }
+    };
+    let block_state_map = Arc::new(Mutex::new(block_state));
+
+    // Return transactions of abandoned branches to the mempool on every reorg
+    let reorgs = blockchain.lock().unwrap().subscribe();
+    miner::start_reorg_handler(reorgs, &mempool, &block_state_map);
 
     // Parse P2P server address
     let p2p_addr = matches
@@ -93,8 +173,54 @@ fn main() {
     // Create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
-    // Start the P2P server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    // Choose which account to use based on the port
+    let address_to_use = p2p_addr.port() % 10;
+    let (chosen_address, chosen_keypair, receiver_addresses) = match address_to_use {
+        1 => (account1, Arc::clone(&pair1), [account0, account2]),
+        2 => (account2, Arc::clone(&pair2), [account0, account1]),
+        _ => (account0, Arc::clone(&pair0), [account1, account2]),
+    };
+
+    // Start the P2P server, announcing our account to peers
+    let target_outbound = matches
+        .value_of("outbound_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peers: {}", e);
+            process::exit(1);
+        });
+    let ban_duration = matches
+        .value_of("ban_duration")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+    let parse_limit = |name: &str, what: &str| {
+        matches.value_of(name).unwrap().parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", what, e);
+            process::exit(1);
+        })
+    };
+    let slow_peer = match matches.value_of("slow_peer").unwrap() {
+        "drop" => network::peer::SlowPeer::Drop,
+        _ => network::peer::SlowPeer::Disconnect,
+    };
+    let server_config = network::server::Config {
+        address: Some(chosen_address),
+        target_outbound,
+        ban_duration: std::time::Duration::from_secs(ban_duration),
+        max_frame_size: parse_limit("max_frame_size", "max frame size") as usize,
+        peer_queue_size: parse_limit("peer_queue_size", "peer queue size") as usize,
+        slow_peer,
+        max_inbound_messages: parse_limit("peer_message_rate", "peer message rate"),
+        max_inbound_bytes: parse_limit("peer_byte_rate", "peer byte rate"),
+        // the node's identity is the key of the account it announces
+        identity: if matches.is_present("encrypt") { Some(Arc::clone(&chosen_keypair)) } else { None },
+    };
+    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx, &blockchain, server_config).unwrap();
     server_ctx.start().unwrap();
 
     // Start the worker
@@ -106,6 +232,7 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+    let download = Arc::new(Mutex::new(network::download::BlockDownload::new()));
     let worker_ctx = network::worker::Worker::new(
         p2p_workers,
         msg_rx,
@@ -113,17 +240,10 @@ fn main() {
         &blockchain,
         &mempool,
         &block_state_map,
+        &download,
     );
     worker_ctx.start();
 
-    // Choose which account to use based on the port
-    let address_to_use = p2p_addr.port() % 10;
-    let (chosen_address, chosen_keypair, receiver_addresses) = match address_to_use {
-        1 => (account1, Arc::clone(&pair1), [account0, account2]),
-        2 => (account2, Arc::clone(&pair2), [account0, account1]),
-        _ => (account0, Arc::clone(&pair0), [account1, account2]),
-    };
-
     // Initialize the TransactionGenerator
     let (finished_tx_sender, finished_tx_receiver) = unbounded();
     let transaction_generator = generator::generator::TransactionGenerator::new(
@@ -143,49 +263,41 @@ fn main() {
         Arc::clone(&blockchain),
         &mempool,
         &block_state_map,
+        chosen_address,
+    );
+    let miner_worker_ctx = miner::worker::Worker::new(
+        &server,
+        finished_block_chan,
+        Arc::clone(&blockchain),
+        &block_state_map,
     );
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, Arc::clone(&blockchain));
     miner_ctx.start();
     miner_worker_ctx.start();
 
-    // Connect to known peers
+    // Seed the address book with the known peers; the server keeps dialing addresses from it,
+    // and those it learns from peers, until it has enough outbound connections
     if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!("Error connecting to peer {}, retrying in one second: {}", addr, e);
-                            thread::sleep(time::Duration::from_millis(1000));
-                        }
-                    }
-                }
+        let mut seeds = vec![];
+        for peer in known_peers {
+            match peer.parse::<net::SocketAddr>() {
+                Ok(addr) => seeds.push((addr, network::address_book::now())),
+                Err(e) => error!("Error parsing peer address {}: {}", peer, e),
             }
-        });
+        }
+        server.add_addresses(seeds);
     }
 
     // Start the API server
-    ApiServer::start(
-        api_addr,
-        &miner,
-        &transaction_generator,
-        &server,
-        &blockchain,
-        &block_state_map,
-    );
+    let api_context = ApiContext {
+        miner: miner.clone(),
+        tx_generator: transaction_generator.clone(),
+        network: server.clone(),
+        blockchain: Arc::clone(&blockchain),
+        block_state: Arc::clone(&block_state_map),
+        mempool: Arc::clone(&mempool),
+        download: Arc::clone(&download),
+    };
+    ApiServer::start(api_addr, api_context);
 
     // Main loop to keep the application running
     loop {
@@ -1,28 +1,40 @@
 use crate::types::block::{Block, Header, Content};
 use crate::types::hash::H256;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 use rand::Rng;
 use log::info;
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, Reorg};
+use crate::types::address::Address;
 use crate::types::hash::Hashable;
 use crate::types::merkle::MerkleTree;
 use crate::types::transaction::SignedTransaction;
-use crate::blockchain::DIFFICULTY;
-use crate::types::transaction::verify;
+use crate::types::transaction::{verify_signed, SignatureError};
 
 
+pub mod rolling_filter;
 pub mod worker;
 
 
 use std::collections::HashSet;
 use crate::types::block::BlockState;
+use crate::types::state::{Account, State};
 
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use rolling_filter::RollingFilter;
+use serde::Serialize;
 use std::thread;
-use std::time;
+use std::time::{self, Duration, Instant};
+
+
+/// Most bytes of transactions, besides the coinbase, a mined block carries
+pub const MAX_BLOCK_SIZE: usize = 4000;
+
+
+/// How often admitting a transaction also drops the expired ones
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 
 enum ControlSignal {
@@ -39,40 +51,649 @@ enum OperatingState {
 }
 
 
+/// Why a transaction was not admitted to the mempool
+#[derive(Debug, Clone, PartialEq)]
+pub enum MempoolError {
+   /// The transaction is already pooled or was already mined
+   AlreadyKnown(H256),
+   /// Coinbase transactions only exist inside blocks
+   Coinbase,
+   /// The signature does not verify under the transaction's public key
+   InvalidSignature,
+   /// The public key does not hash to the sender, so the sender did not sign it
+   WrongSender { sender: Address, signer: Address },
+   /// The tip state has already used the nonce
+   StaleNonce { sender: Address, state_nonce: u32, found: u32 },
+   /// What the sender's balance at the tip leaves after its pooled transactions cannot pay the
+   /// value plus fee
+   InsufficientBalance { sender: Address, balance: u64, cost: u64 },
+   /// The value plus fee does not fit in a u64
+   Overflow(Address),
+   /// The sender already has as many pending transactions as one sender may
+   SenderLimit { sender: Address, limit: usize },
+   /// The pool is full of transactions paying more
+   PoolFull,
+   /// The sender already has a pooled transaction with this nonce, and this one does not pay
+   /// enough more to replace it
+   ReplacementUnderpriced { replaced: H256, required: u64, found: u64 },
+}
+
+impl MempoolError {
+   /// Whether only a faulty or malicious peer relays such a transaction; the others can be
+   /// honest peers behind or ahead of our tip
+   pub fn is_misbehavior(&self) -> bool {
+       matches!(
+           self,
+           MempoolError::Coinbase
+               | MempoolError::InvalidSignature
+               | MempoolError::WrongSender { .. }
+               | MempoolError::Overflow(_)
+       )
+   }
+}
+
+impl std::fmt::Display for MempoolError {
+   fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+       match self {
+           MempoolError::AlreadyKnown(tx) => write!(f, "transaction {} is already known", tx),
+           MempoolError::Coinbase => write!(f, "coinbase transactions are only valid in blocks"),
+           MempoolError::InvalidSignature => write!(f, "signature does not verify"),
+           MempoolError::WrongSender { sender, signer } => {
+               write!(f, "transaction from {} is signed by the key of {}", sender, signer)
+           }
+           MempoolError::StaleNonce { sender, state_nonce, found } => {
+               write!(f, "nonce {} of sender {} is already used, next is {}", found, sender, *state_nonce as u64 + 1)
+           }
+           MempoolError::InsufficientBalance { sender, balance, cost } => {
+               write!(f, "sender {} cannot pay {} with balance {}", sender, cost, balance)
+           }
+           MempoolError::Overflow(sender) => write!(f, "value plus fee of transaction from {} overflows", sender),
+           MempoolError::SenderLimit { sender, limit } => {
+               write!(f, "sender {} already has {} pending transactions", sender, limit)
+           }
+           MempoolError::PoolFull => write!(f, "mempool is full of transactions paying a higher fee"),
+           MempoolError::ReplacementUnderpriced { replaced, required, found } => {
+               write!(f, "fee {} is too low to replace transaction {}, at least {} is required", found, replaced, required)
+           }
+       }
+   }
+}
+
+impl std::error::Error for MempoolError {}
+
+impl From<SignatureError> for MempoolError {
+   fn from(e: SignatureError) -> Self {
+       match e {
+           SignatureError::WrongSender { sender, signer } => MempoolError::WrongSender { sender, signer },
+           SignatureError::InvalidSignature => MempoolError::InvalidSignature,
+       }
+   }
+}
+
+/// Limits on what the mempool holds
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+   /// Most transactions pooled at once
+   pub max_count: usize,
+   /// Most bytes of serialized transactions pooled at once
+   pub max_bytes: usize,
+   /// Most pending transactions from a single sender
+   pub max_per_sender: usize,
+   /// How long a transaction waits to be mined before it is dropped
+   pub expiry: Duration,
+   /// Number of transaction hashes remembered as seen, so they are not fetched or pooled again
+   pub seen_capacity: usize,
+   /// How much higher, in percent, the fee of a transaction has to be to replace a pooled one
+   /// with the same sender and nonce; it has to be higher by at least 1 in any case
+   pub replacement_bump: u64,
+}
+
+impl Default for MempoolConfig {
+   fn default() -> Self {
+       Self {
+           max_count: 10_000,
+           max_bytes: 10 * 1024 * 1024,
+           max_per_sender: 100,
+           expiry: Duration::from_secs(3 * 60 * 60),
+           seen_capacity: 100_000,
+           replacement_bump: 10,
+       }
+   }
+}
+
+/// How full the mempool is and what it dropped, as reported by the API
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct MempoolStats {
+   pub count: usize,
+   /// Transactions that can be mined on top of the tip, in nonce order
+   pub ready: usize,
+   /// Transactions waiting for a lower nonce of their sender
+   pub future: usize,
+   pub bytes: usize,
+   /// Transactions dropped to make room for better paying ones
+   pub evicted: u64,
+   /// Transactions dropped because they waited longer than the expiry
+   pub expired: u64,
+   /// Transactions turned away because the pool was full of better paying ones
+   pub rejected_full: u64,
+   /// Transactions turned away because their sender had too many pending
+   pub rejected_sender_limit: u64,
+   /// Transactions replaced by one with the same sender and nonce paying a higher fee
+   pub replaced: u64,
+}
+
+/// The pooled transactions of one sender, by nonce
+#[derive(Debug, Default)]
+struct SenderQueue {
+   /// The sender's nonce in the tip state, as of the last time the pool saw it
+   state_nonce: u32,
+   transactions: BTreeMap<u32, H256>,
+}
+
+impl SenderQueue {
+   /// The transactions that can be mined in a row on top of a state where the sender's nonce
+   /// is `state_nonce`: consecutive nonces starting right after it
+   fn ready_after(&self, state_nonce: u32) -> impl Iterator<Item = &H256> {
+       let mut next = state_nonce.checked_add(1);
+       self.transactions.range(next.unwrap_or(u32::MAX)..).map_while(move |(nonce, tx_hash)| {
+           if Some(*nonce) != next {
+               return None;
+           }
+           next = nonce.checked_add(1);
+           Some(tx_hash)
+       })
+   }
+
+   /// Number of ready transactions; the rest wait behind a nonce gap
+   fn ready_count(&self) -> usize {
+       self.ready_after(self.state_nonce).count()
+   }
+}
+
+/// Bookkeeping for a pooled transaction
+#[derive(Debug, Clone, Copy)]
+struct PoolEntry {
+   size: usize,
+   added: Instant,
+}
+
 pub struct Mempool {
    //map is used to store Txs not added yet to the blockchain
    pub transaction_map: HashMap<H256, SignedTransaction>,
-   //set is used as a record for all transactions added to blockchain
-   pub transaction_set: HashSet<H256>
+   //set is used as a record for the transactions recently pooled or added to blockchain
+   pub transaction_set: RollingFilter,
+   entries: HashMap<H256, PoolEntry>,
+   /// Pooled transactions of each sender, in nonce order
+   queues: HashMap<Address, SenderQueue>,
+   bytes: usize,
+   config: MempoolConfig,
+   stats: MempoolStats,
+   last_expiry: Instant,
 }
 //implement Mempool like Blockchain
 impl Mempool {
    pub fn new() -> Self {
-       return Mempool {
+       Self::with_config(MempoolConfig::default())
+   }
+
+
+   pub fn with_config(config: MempoolConfig) -> Self {
+       Mempool {
            transaction_map: HashMap::<H256, SignedTransaction>::new(),
-           transaction_set: HashSet::<H256>::new()
+           transaction_set: RollingFilter::new(config.seen_capacity),
+           entries: HashMap::new(),
+           queues: HashMap::new(),
+           bytes: 0,
+           config,
+           stats: MempoolStats::default(),
+           last_expiry: Instant::now(),
        }
    }
 
 
+   /// Pool a transaction we already know to be valid, evicting others if the pool is full
    pub fn insert(&mut self, transaction: &SignedTransaction) {
-       if self.transaction_set.contains(&transaction.hash()) {
-           return;
+       let tx_hash = transaction.hash();
+       if self.add(transaction, transaction_size(transaction)) && !self.make_room(&tx_hash) {
+           self.stats.rejected_full += 1;
+       }
+   }
+
+
+   /// Pool `transaction`, `size` bytes long, without checking any limit. Returns false if it
+   /// was already seen or its sender has another transaction with the same nonce pooled.
+   fn add(&mut self, transaction: &SignedTransaction, size: usize) -> bool {
+       let tx_hash = transaction.hash();
+       let tx = &transaction.transaction;
+       if self.transaction_set.contains(&tx_hash) || self.pooled_nonce(&tx.sender, tx.account_nonce).is_some() {
+           return false;
        }
-       self.transaction_map.insert(transaction.hash(), transaction.clone());
-       self.transaction_set.insert(transaction.hash());
-       // println!("Mempool - Inserting transaction: {:?}", transaction.hash());
+       self.transaction_map.insert(tx_hash, transaction.clone());
+       self.transaction_set.insert(tx_hash);
+       self.entries.insert(tx_hash, PoolEntry { size, added: Instant::now() });
+       self.queues.entry(tx.sender).or_default().transactions.insert(tx.account_nonce, tx_hash);
+       self.bytes += size;
+       true
+   }
+
+
+   /// The pooled transaction of `sender` with `nonce`, if any
+   pub fn pooled_nonce(&self, sender: &Address, nonce: u32) -> Option<H256> {
+       self.queues.get(sender).and_then(|queue| queue.transactions.get(&nonce)).copied()
+   }
+
+
+   /// The pooled transaction that `transaction` conflicts with: another one of the same sender
+   /// with the same nonce. The pool holds at most one transaction per sender and nonce, so a
+   /// block assembled from it never contains two conflicting ones.
+   pub fn conflict(&self, transaction: &SignedTransaction) -> Option<H256> {
+       let tx = &transaction.transaction;
+       self.pooled_nonce(&tx.sender, tx.account_nonce).filter(|pooled| *pooled != transaction.hash())
+   }
+
+
+   /// What the pooled transactions of `sender` after `state_nonce` cost it in value plus fee,
+   /// leaving out `excluded`. None if the sum overflows.
+   fn queued_cost(&self, sender: &Address, state_nonce: u32, excluded: Option<&H256>) -> Option<u64> {
+       let queue = match self.queues.get(sender) {
+           Some(queue) => queue,
+           None => return Some(0),
+       };
+       queue.transactions
+           .range(state_nonce.checked_add(1)?..)
+           .filter(|(_, tx_hash)| Some(*tx_hash) != excluded)
+           .try_fold(0u64, |sum, (_, tx_hash)| {
+               let tx = &self.transaction_map[tx_hash].transaction;
+               sum.checked_add(tx.value)?.checked_add(tx.fee)
+           })
+   }
+
+
+   /// Evict the lowest paying transactions until the pool is within its limits. Returns false
+   /// if `incoming` was among them.
+   fn make_room(&mut self, incoming: &H256) -> bool {
+       while self.transaction_map.len() > self.config.max_count || self.bytes > self.config.max_bytes {
+           let victim = match self.eviction_candidate() {
+               Some(victim) => victim,
+               None => break,
+           };
+           self.remove(&victim);
+           if victim == *incoming {
+               return false;
+           }
+           self.stats.evicted += 1;
+       }
+       true
+   }
+
+
+   /// The transaction to evict first: among the last pending transaction of every sender (so
+   /// no sender is left with a nonce gap), the lowest fee per byte, and the oldest of those
+   fn eviction_candidate(&self) -> Option<H256> {
+       self.queues.values()
+           .filter_map(|queue| queue.transactions.values().next_back())
+           .map(|tx_hash| (tx_hash, self.transaction_map[tx_hash].transaction.fee, self.entries[tx_hash]))
+           .min_by(|a, b| eviction_order(*a, *b))
+           .map(|(tx_hash, _, _)| *tx_hash)
+   }
+
+
+   /// Whether `transaction`, `size` bytes long, would stay in the pool once pooled in place of
+   /// `replaced`, that is whether `make_room` would be done evicting before getting to it.
+   /// Nothing is changed, so a transaction that does not fit costs the pool nothing.
+   fn has_room(&self, transaction: &SignedTransaction, size: usize, replaced: Option<&H256>) -> bool {
+       let tx_hash = transaction.hash();
+       let tx = &transaction.transaction;
+       let incoming = PoolEntry { size, added: Instant::now() };
+       let mut count = self.transaction_map.len() + 1 - replaced.map_or(0, |_| 1);
+       let mut bytes = self.bytes + size - replaced.map_or(0, |replaced| self.entries[replaced].size);
+       if count <= self.config.max_count && bytes <= self.config.max_bytes {
+           return true;
+       }
+       // the sender's queue as it would be, with `transaction` in its nonce slot
+       let mut own = self.queues.get(&tx.sender).map(|queue| queue.transactions.clone()).unwrap_or_default();
+       own.insert(tx.account_nonce, tx_hash);
+       // every sender's transactions in the order `make_room` reaches them, last nonce first
+       let mut tails: Vec<_> = self.queues.iter()
+           .filter(|(sender, _)| **sender != tx.sender)
+           .map(|(_, queue)| queue.transactions.values().rev().peekable())
+           .collect();
+       tails.push(own.values().rev().peekable());
+       let entry = |tx_hash: &H256| match self.transaction_map.get(tx_hash) {
+           Some(pooled) => (pooled.transaction.fee, self.entries[tx_hash]),
+           None => (tx.fee, incoming),
+       };
+       while count > self.config.max_count || bytes > self.config.max_bytes {
+           let victim = tails.iter_mut()
+               .enumerate()
+               .filter_map(|(i, tail)| tail.peek().map(|tx_hash| (i, **tx_hash)))
+               .min_by(|(_, a), (_, b)| {
+                   let (a_fee, a_entry) = entry(a);
+                   let (b_fee, b_entry) = entry(b);
+                   eviction_order((a, a_fee, a_entry), (b, b_fee, b_entry))
+               });
+           let (i, victim) = match victim {
+               Some(victim) => victim,
+               None => break,
+           };
+           if victim == tx_hash {
+               return false;
+           }
+           tails[i].next();
+           count -= 1;
+           bytes -= self.entries[&victim].size;
+       }
+       true
+   }
+
+
+   /// Drop transactions that waited longer than the configured expiry
+   pub fn expire(&mut self) {
+       self.last_expiry = Instant::now();
+       let expiry = self.config.expiry;
+       let expired: Vec<H256> = self.entries.iter()
+           .filter(|(_, entry)| entry.added.elapsed() >= expiry)
+           .map(|(tx_hash, _)| *tx_hash)
+           .collect();
+       for tx_hash in expired {
+           self.remove(&tx_hash);
+           self.stats.expired += 1;
+       }
+   }
+
+
+   pub fn stats(&self) -> MempoolStats {
+       let ready = self.queues.values().map(SenderQueue::ready_count).sum();
+       MempoolStats {
+           count: self.transaction_map.len(),
+           ready,
+           future: self.transaction_map.len() - ready,
+           bytes: self.bytes,
+           ..self.stats
+       }
+   }
+
+
+   /// Pool `transaction` if it is new, properly signed by its sender, and `account`, the
+   /// sender's account at the tip, has not used its nonce and can pay for it. Nonces past the
+   /// next one are accepted; the miner holds them back until the gap is filled. A transaction
+   /// conflicting with a pooled one replaces it if it pays a high enough fee. When the pool
+   /// is full, the lowest paying transactions make room, unless this one pays the least.
+   pub fn admit(&mut self, transaction: &SignedTransaction, account: &Account) -> Result<(), MempoolError> {
+       if self.last_expiry.elapsed() >= EXPIRY_CHECK_INTERVAL {
+           self.expire();
+       }
+       let tx_hash = transaction.hash();
+       if self.transaction_set.contains(&tx_hash) {
+           return Err(MempoolError::AlreadyKnown(tx_hash));
+       }
+       if transaction.is_coinbase() {
+           return Err(MempoolError::Coinbase);
+       }
+       verify_signed(transaction)?;
+       let tx = &transaction.transaction;
+       if tx.account_nonce <= account.nonce {
+           return Err(MempoolError::StaleNonce { sender: tx.sender, state_nonce: account.nonce, found: tx.account_nonce });
+       }
+       let replaced = self.conflict(transaction);
+       if let Some(replaced) = replaced {
+           let fee = self.transaction_map[&replaced].transaction.fee;
+           let bump = (fee.saturating_mul(self.config.replacement_bump) / 100).max(1);
+           let required = fee.saturating_add(bump);
+           if tx.fee < required {
+               return Err(MempoolError::ReplacementUnderpriced { replaced, required, found: tx.fee });
+           }
+       }
+       let cost = tx.value.checked_add(tx.fee).ok_or(MempoolError::Overflow(tx.sender))?;
+       // the sender pays for its pooled transactions first, except the one this replaces
+       let queued = self.queued_cost(&tx.sender, account.nonce, replaced.as_ref())
+           .ok_or(MempoolError::Overflow(tx.sender))?;
+       let balance = account.balance.saturating_sub(queued);
+       if cost > balance {
+           return Err(MempoolError::InsufficientBalance { sender: tx.sender, balance, cost });
+       }
+       let pending = self.queues.get(&tx.sender).map_or(0, |queue| queue.transactions.len());
+       if replaced.is_none() && pending >= self.config.max_per_sender {
+           self.stats.rejected_sender_limit += 1;
+           return Err(MempoolError::SenderLimit { sender: tx.sender, limit: self.config.max_per_sender });
+       }
+       // make sure the transaction fits before anything is evicted or replaced for it
+       let size = transaction_size(transaction);
+       if !self.has_room(transaction, size, replaced.as_ref()) {
+           self.stats.rejected_full += 1;
+           return Err(MempoolError::PoolFull);
+       }
+       if let Some(replaced) = replaced {
+           self.remove(&replaced);
+       }
+       self.add(transaction, size);
+       self.queues.get_mut(&tx.sender).unwrap().state_nonce = account.nonce;
+       self.make_room(&tx_hash);
+       if let Some(replaced) = replaced {
+           info!("Transaction {} replaces {}", tx_hash, replaced);
+           self.stats.replaced += 1;
+       }
+       Ok(())
    }
 
 
    pub fn remove(&mut self, transaction_hash: &H256) {
-       if self.transaction_map.contains_key(&transaction_hash) {
-           self.transaction_map.remove(&transaction_hash);
+       if let Some(tx) = self.transaction_map.remove(transaction_hash) {
+           let sender = tx.transaction.sender;
+           if let Some(queue) = self.queues.get_mut(&sender) {
+               queue.transactions.remove(&tx.transaction.account_nonce);
+               if queue.transactions.is_empty() {
+                   self.queues.remove(&sender);
+               }
+           }
+           if let Some(entry) = self.entries.remove(transaction_hash) {
+               self.bytes -= entry.size;
+           }
+       }
+   }
+
+
+   /// Record the senders' nonces in `state`, the new tip state, to tell ready transactions from
+   /// future ones
+   fn update_nonces(&mut self, state: &State) {
+       for (sender, queue) in self.queues.iter_mut() {
+           queue.state_nonce = state.get(sender).nonce;
+       }
+   }
+
+
+   /// Keep only the pooled transactions `keep` returns true for
+   fn retain<F: FnMut(&H256, &SignedTransaction) -> bool>(&mut self, mut keep: F) {
+       let dropped: Vec<H256> = self.transaction_map.iter()
+           .filter(|(tx_hash, tx)| !keep(tx_hash, tx))
+           .map(|(tx_hash, _)| *tx_hash)
+           .collect();
+       for tx_hash in dropped {
+           self.remove(&tx_hash);
+       }
+   }
+
+
+   /// Drop pending transactions whose nonce `state` has already used
+   pub fn remove_stale(&mut self, state: &State) {
+       self.retain(|_, tx| tx.transaction.account_nonce > state.get(&tx.transaction.sender).nonce);
+       self.update_nonces(state);
+   }
+
+
+   /// Pick the transactions of the next block on top of `state`, highest fee per byte first,
+   /// while keeping every sender's transactions in nonce order. `state` is updated with the
+   /// selected transactions. Returns them together with the fees they pay. Signatures were
+   /// checked when the transactions were admitted and are checked again by the worker when the
+   /// block is found, so they are not verified here on every nonce attempt.
+   pub fn select_transactions(
+       &self,
+       state: &mut State,
+       block_limit: usize,
+   ) -> (Vec<SignedTransaction>, u64) {
+       // the ready transactions of every sender, with the lowest nonce last so it can be popped
+       let mut queues: HashMap<Address, Vec<(&SignedTransaction, H256, usize)>> = self.queues
+           .iter()
+           .map(|(sender, queue)| {
+               let mut ready: Vec<(&SignedTransaction, H256, usize)> = queue
+                   .ready_after(state.get(sender).nonce)
+                   .map(|tx_hash| (&self.transaction_map[tx_hash], *tx_hash, self.entries[tx_hash].size))
+                   .collect();
+               ready.reverse();
+               (*sender, ready)
+           })
+           .filter(|(_, ready)| !ready.is_empty())
+           .collect();
+
+       let mut selected = Vec::new();
+       let mut fees: u64 = 0;
+       let mut current_size = 0;
+       loop {
+           // highest fee per byte wins, ties go to the lower hash
+           let best = queues.iter()
+               .map(|(sender, queue)| (*sender, *queue.last().unwrap()))
+               .max_by(|(_, a), (_, b)| {
+                   let a_rate = a.0.transaction.fee as u128 * b.2 as u128;
+                   let b_rate = b.0.transaction.fee as u128 * a.2 as u128;
+                   a_rate.cmp(&b_rate).then(b.1.cmp(&a.1))
+               });
+           let (sender, (tx, _, size)) = match best {
+               Some(best) => best,
+               None => break,
+           };
+
+           let fits = current_size + size <= block_limit && fees.checked_add(tx.transaction.fee).is_some();
+           if !fits || state.apply_transaction(tx).is_err() {
+               // later nonces of this sender depend on this one
+               queues.remove(&sender);
+               continue;
+           }
+           fees += tx.transaction.fee;
+           current_size += size;
+           selected.push(tx.clone());
+           let queue = queues.get_mut(&sender).unwrap();
+           queue.pop();
+           if queue.is_empty() {
+               queues.remove(&sender);
+           }
+       }
+       (selected, fees)
+   }
+
+
+   /// Bring the pool in line with a switch of the longest chain: transactions of disconnected
+   /// blocks that the new branch did not include go back into the pool, and pending
+   /// transactions whose nonce the new tip state has already used are dropped. Every sender's
+   /// queue is then replayed in nonce order against the new tip state, dropping from the first
+   /// transaction the sender can no longer pay for or that exceeds its pending limit on.
+   pub fn reorg(&mut self, reorg: &Reorg, tip_state: &State) {
+       let connected: HashSet<H256> = reorg.connected.iter()
+           .flat_map(|block| block.content.transactions.iter().map(|tx| tx.hash()))
+           .collect();
+       for block in reorg.disconnected.iter() {
+           for tx in block.content.transactions.iter() {
+               let tx_hash = tx.hash();
+               if connected.contains(&tx_hash) || tx.is_coinbase() {
+                   continue;
+               }
+               // forget the transaction was seen so it can be pooled and mined again
+               self.transaction_set.remove(&tx_hash);
+               self.insert(tx);
+           }
        }
+       self.retain(|tx_hash, tx| {
+           let sender_nonce = tip_state.get(&tx.transaction.sender).nonce;
+           !connected.contains(tx_hash) && tx.transaction.account_nonce > sender_nonce
+       });
+       let mut dropped = Vec::new();
+       for (sender, queue) in self.queues.iter() {
+           let mut balance = tip_state.get(sender).balance;
+           let unpaid = queue.transactions.values().enumerate().position(|(i, tx_hash)| {
+               let tx = &self.transaction_map[tx_hash].transaction;
+               match tx.value.checked_add(tx.fee).and_then(|cost| balance.checked_sub(cost)) {
+                   Some(left) if i < self.config.max_per_sender => {
+                       balance = left;
+                       false
+                   }
+                   _ => true,
+               }
+           });
+           // later nonces of this sender depend on the first one dropped
+           if let Some(unpaid) = unpaid {
+               dropped.extend(queue.transactions.values().skip(unpaid).copied());
+           }
+       }
+       for tx_hash in dropped.iter() {
+           self.remove(tx_hash);
+       }
+       self.transaction_set.extend(connected);
+       self.update_nonces(tip_state);
    }
 }
 
 
+/// Size of a transaction as the pool accounts for it
+fn transaction_size(transaction: &SignedTransaction) -> usize {
+   bincode::serialize(transaction).unwrap().len()
+}
+
+
+/// The order pooled transactions are evicted in: the lowest fee per byte first, then the oldest
+fn eviction_order(
+   (a_hash, a_fee, a): (&H256, u64, PoolEntry),
+   (b_hash, b_fee, b): (&H256, u64, PoolEntry),
+) -> std::cmp::Ordering {
+   let a_rate = a_fee as u128 * b.size as u128;
+   let b_rate = b_fee as u128 * a.size as u128;
+   a_rate.cmp(&b_rate).then(a.added.cmp(&b.added)).then(a_hash.cmp(b_hash))
+}
+
+
+/// Admit `tx` to `mempool` against the state at the tip of `blockchain`
+pub fn admit_transaction(
+   tx: &SignedTransaction,
+   blockchain: &Mutex<Blockchain>,
+   block_state_map: &Mutex<BlockState>,
+   mempool: &Mutex<Mempool>,
+) -> Result<(), MempoolError> {
+   let tip = blockchain.lock().unwrap().tip();
+   // the miner locks the state map while holding the mempool, so release it first
+   let account = block_state_map.lock().unwrap()
+       .block_state_map
+       .get(&tip)
+       .map(|state| state.get(&tx.transaction.sender))
+       .unwrap_or_default();
+   mempool.lock().unwrap().admit(tx, &account)
+}
+
+
+/// Keep the mempool consistent with the longest chain by applying every reorg the
+/// blockchain emits, using the state of the new tip.
+pub fn start_reorg_handler(
+   reorgs: Receiver<Reorg>,
+   mempool: &Arc<Mutex<Mempool>>,
+   block_state_map: &Arc<Mutex<BlockState>>,
+) {
+   let mempool = Arc::clone(mempool);
+   let block_state_map = Arc::clone(block_state_map);
+   thread::Builder::new()
+       .name("mempool-reorg".to_string())
+       .spawn(move || {
+           for reorg in reorgs.iter() {
+               let tip_state = match block_state_map.lock().unwrap().block_state_map.get(&reorg.new_tip) {
+                   Some(state) => state.clone(),
+                   None => {
+                       info!("No state found for new tip {:?}, skipping mempool update", reorg.new_tip);
+                       continue;
+                   }
+               };
+               mempool.lock().unwrap().reorg(&reorg, &tip_state);
+           }
+       })
+       .unwrap();
+}
+
+
 pub struct Context {
    /// Channel for receiving control signal
    control_chan: Receiver<ControlSignal>,
@@ -81,6 +702,8 @@ pub struct Context {
    blockchain: Arc<Mutex<Blockchain>>,
    mempool: Arc<Mutex<Mempool>>,
    block_state_map: Arc<Mutex<BlockState>>,
+   /// Address the coinbase of every mined block pays to
+   address: Address,
 }
 
 
@@ -92,7 +715,7 @@ pub struct Handle {
 
 
 pub fn new(blockchain: Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>,
-   block_state_map: &Arc<Mutex<BlockState>>) -> (Context, Handle, Receiver<Block>) {
+   block_state_map: &Arc<Mutex<BlockState>>, address: Address) -> (Context, Handle, Receiver<Block>) {
    let (signal_chan_sender, signal_chan_receiver) = unbounded();
    let (finished_block_sender, finished_block_receiver) = unbounded();
 
@@ -104,6 +727,7 @@ pub fn new(blockchain: Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>,
        blockchain: Arc::clone(&blockchain),
        mempool: Arc::clone(mempool),
        block_state_map: Arc::clone(block_state_map),
+       address,
    };
 
 
@@ -121,7 +745,7 @@ fn test_new() -> (Context, Handle, Receiver<Block>) {
    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
    let mempool = Arc::new(Mutex::new(Mempool::new()));
    let block_state_map = Arc::new(Mutex::new(BlockState::new()));
-   new(blockchain, &mempool, &block_state_map)
+   new(blockchain, &mempool, &block_state_map, Address::from([7; 20]))
 }
 
 
@@ -203,11 +827,15 @@ impl Context {
             return;
         }
 
-        let parent_ = self.blockchain.lock().unwrap().tip();
+        let (parent_, parent_height, difficulty_, subsidy) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let tip = blockchain.tip();
+            let height = blockchain.heights[&tip];
+            (tip, height, blockchain.next_difficulty(&tip), blockchain.reward_schedule.subsidy(height + 1))
+        };
         let start = SystemTime::now();
         let mut rng = rand::thread_rng();
         let timestamp_ = start.duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
-        let difficulty_: H256 = DIFFICULTY.into();
 
         // Retrieve the state of the current block
         let mut tip_state = match self.block_state_map.lock() {
@@ -224,7 +852,6 @@ impl Context {
             }
         };
 
-        let mut transactions = Vec::<SignedTransaction>::new();
         let mut mempool = match self.mempool.lock() {
             Ok(mempool) => mempool,
             Err(_) => {
@@ -232,59 +859,25 @@ impl Context {
                 continue; // Skip this iteration if we can't lock the mempool
             }
         };
-        let block_limit = 4000;
-        let mut current_size = 0;
-        let mut bytes: Vec<u8>;
-
-        for (_, tx) in mempool.transaction_map.clone().iter() {
-            bytes = bincode::serialize(&tx).unwrap();
-            if current_size + bytes.len() > block_limit {
-                break;
-            }
-
-            ///////////State checks///////////
-            let transaction = &tx.transaction;
-            let sender_state;
-            if tip_state.contains_key(&transaction.sender) {
-                sender_state = tip_state.get(&transaction.sender).unwrap().clone();
-            } else {
-                sender_state = (0, 0);
-            }
-
-            // Check if the sender has enough balance and if the nonce is correct
-            if transaction.value > sender_state.1 || transaction.account_nonce != sender_state.0 + 1 {
-                // Remove transactions with incorrect nonce or insufficient balance
-                if transaction.account_nonce < sender_state.1 {
-                    mempool.remove(&tx.hash());
-                }
-                //println!("Transaction value: {}", transaction.value);
-                // println!("Sender balance: {}", sender_state.1);
-                // println!("Sender nonce: {}", sender_state.0);
-                // println!("Account nonce: {}", transaction.account_nonce);
-                // println!("Skipping invalid transaction for sender: {:?}", transaction.sender);
+        // Remove transactions the tip state has already used the nonce of, then fill the
+        // block by fee per byte
+        mempool.remove_stale(&tip_state);
+        let (mut transactions, fees) = mempool.select_transactions(&mut tip_state, MAX_BLOCK_SIZE);
+
+        // The coinbase goes first and pays the block subsidy plus the fees to our address
+        let reward = match subsidy.checked_add(fees) {
+            Some(reward) => reward,
+            None => {
+                info!("Block subsidy plus fees overflows");
                 continue;
             }
-
-            //println!("Not skipped");
-
-            // At this point, the transaction is valid, so update the local state copy
-            tip_state.insert(transaction.sender, (sender_state.0 + 1, sender_state.1 - transaction.value));
-
-            let receiver_state;
-            if tip_state.contains_key(&transaction.receiver) {
-                receiver_state = tip_state.get(&transaction.receiver).unwrap().clone();
-            } else {
-                receiver_state = (0, 0);
-            }
-
-            // Update receiver state
-            tip_state.insert(transaction.receiver, (receiver_state.0, receiver_state.1 + transaction.value));
-
-            ////////////////////////////////
-
-            current_size += bytes.len();
-            transactions.push(tx.clone());
+        };
+        let coinbase = SignedTransaction::coinbase(self.address, reward, parent_height + 1);
+        if let Err(e) = tip_state.apply_transaction(&coinbase) {
+            info!("Cannot pay the block reward: {}", e);
+            continue;
         }
+        transactions.insert(0, coinbase);
 
         let merkle_tree_ = MerkleTree::new(&transactions);
         let nonce_ = rng.gen::<u32>();
@@ -310,24 +903,8 @@ impl Context {
                 mempool.remove(&tx.hash());
             }
 
-            // Add the block state to the block_state_map
-            // After mining a block and before inserting it into the blockchain
-            let mut block_state_map = self.block_state_map.lock().unwrap();
-            block_state_map.block_state_map.insert(block.hash(), tip_state.clone());
-
-            // Debug: Check that the block state has been added correctly
-            println!("Inserted block state for block hash {:?}: {:?}", block.hash(), tip_state);
-
             // Remove invalid transactions after state update
-            for (_, tx) in mempool.transaction_map.clone().iter() {
-                let sender = tx.transaction.sender;
-                let sender_state = tip_state.get(&sender).unwrap().clone();
-                if tx.transaction.value > sender_state.1 || tx.transaction.account_nonce != sender_state.0 + 1 {
-                    if tx.transaction.account_nonce < sender_state.1 {
-                        mempool.remove(&tx.hash());
-                    }
-                }
-            }
+            mempool.remove_stale(&tip_state);
 
             // Send the mined block to the finished block channel
             self.finished_block_chan.send(block.clone()).expect("Send finished block error");
@@ -371,6 +948,318 @@ mod test {
    }
 
 
+   #[test]
+   fn reorg_returns_abandoned_transactions() {
+       use crate::blockchain::Reorg;
+       use crate::types::block::generate_random_block;
+       use crate::types::hash::generate_random_hash;
+       use crate::types::key_pair;
+       use crate::types::state::State;
+       use crate::types::transaction::signed_transfer;
+
+       let s_key = key_pair::random();
+       let t_key = key_pair::random();
+       let only_old = signed_transfer(&s_key, 1, 10, 0);
+       let on_both = signed_transfer(&t_key, 1, 10, 0);
+       let pending = signed_transfer(&t_key, 1, 20, 0);
+       let t = on_both.transaction.sender;
+
+       let mut old_block = generate_random_block(&generate_random_hash());
+       old_block.content.transactions = vec![only_old.clone(), on_both.clone()];
+       let mut new_block = generate_random_block(&generate_random_hash());
+       new_block.content.transactions = vec![on_both.clone()];
+
+       let mut mempool = super::Mempool::new();
+       mempool.insert(&only_old);
+       mempool.insert(&on_both);
+       mempool.remove(&only_old.hash());
+       mempool.remove(&on_both.hash());
+       mempool.insert(&pending);
+
+       let reorg = Reorg {
+           old_tip: old_block.hash(),
+           new_tip: new_block.hash(),
+           common_ancestor: generate_random_hash(),
+           disconnected: vec![old_block],
+           connected: vec![new_block],
+       };
+       let mut tip_state = State::new();
+       tip_state.credit(only_old.transaction.sender, 10).unwrap();
+       tip_state.credit(t, 10).unwrap();
+       tip_state.apply_transaction(&on_both).unwrap();
+       mempool.reorg(&reorg, &tip_state);
+
+       assert_eq!(mempool.transaction_map.len(), 1);
+       assert!(mempool.transaction_map.contains_key(&only_old.hash()));
+       assert!(mempool.transaction_set.contains(&on_both.hash()));
+   }
+
+
+   #[test]
+   fn reorg_drops_what_the_new_tip_cannot_pay_for() {
+       use super::MempoolConfig;
+       use crate::blockchain::Reorg;
+       use crate::types::block::generate_random_block;
+       use crate::types::hash::generate_random_hash;
+       use crate::types::key_pair;
+       use crate::types::state::State;
+       use crate::types::transaction::signed_transfer;
+
+       let s_key = key_pair::random();
+       let u_key = key_pair::random();
+       let s: Vec<_> = (1..=3).map(|nonce| signed_transfer(&s_key, nonce, 9, 1)).collect();
+       let u: Vec<_> = (1..=3).map(|nonce| signed_transfer(&u_key, nonce, 9, 1)).collect();
+
+       let mut old_block = generate_random_block(&generate_random_hash());
+       old_block.content.transactions = s.iter().chain(u.iter()).cloned().collect();
+       let new_block = generate_random_block(&generate_random_hash());
+       let reorg = Reorg {
+           old_tip: old_block.hash(),
+           new_tip: new_block.hash(),
+           common_ancestor: generate_random_hash(),
+           disconnected: vec![old_block],
+           connected: vec![new_block],
+       };
+       // on the new branch `s` only has the funds for its first transfer
+       let mut tip_state = State::new();
+       tip_state.credit(s[0].transaction.sender, 15).unwrap();
+       tip_state.credit(u[0].transaction.sender, 100).unwrap();
+
+       let mut mempool = super::Mempool::with_config(MempoolConfig { max_per_sender: 2, ..Default::default() });
+       mempool.reorg(&reorg, &tip_state);
+
+       let mut pooled: Vec<_> = mempool.transaction_map.keys().copied().collect();
+       pooled.sort();
+       let mut expected = vec![s[0].hash(), u[0].hash(), u[1].hash()];
+       expected.sort();
+       assert_eq!(pooled, expected);
+   }
+
+
+   #[test]
+   fn selection_prefers_fees_and_keeps_nonce_order() {
+       use crate::types::address::Address;
+       use crate::types::key_pair;
+       use crate::types::state::{Account, State};
+       use crate::types::transaction::signed_transfer;
+       use ring::signature::KeyPair;
+
+       let s_key = key_pair::random();
+       let t_key = key_pair::random();
+       let s = Address::from_public_key_bytes(s_key.public_key().as_ref());
+       let t = Address::from_public_key_bytes(t_key.public_key().as_ref());
+       let s1 = signed_transfer(&s_key, 1, 10, 1);
+       let s2 = signed_transfer(&s_key, 2, 10, 9);
+       let t1 = signed_transfer(&t_key, 1, 10, 5);
+       let t3 = signed_transfer(&t_key, 3, 10, 8);
+
+       let mut mempool = super::Mempool::new();
+       for tx in [&s1, &s2, &t1, &t3].iter() {
+           mempool.insert(tx);
+       }
+       let mut state = State::new();
+       state.credit(s, 100).unwrap();
+       state.credit(t, 100).unwrap();
+       let genesis_state = state.clone();
+
+       let (selected, fees) = mempool.select_transactions(&mut state, 4000);
+       let selected: Vec<_> = selected.iter().map(|tx| tx.hash()).collect();
+       // t1 pays more than s1, s2 has to wait for s1, and t3 has a nonce gap
+       assert_eq!(selected, vec![t1.hash(), s1.hash(), s2.hash()]);
+       assert_eq!(fees, 15);
+       assert_eq!(state.get(&s), Account { nonce: 2, balance: 100 - 20 - 10 });
+       assert_eq!(state.get(&t), Account { nonce: 1, balance: 100 - 10 - 5 });
+
+       // a block limit that fits a single transaction takes the best paying one
+       let mut state = genesis_state;
+       let size = bincode::serialize(&t1).unwrap().len();
+       let (selected, _) = mempool.select_transactions(&mut state, size);
+       assert_eq!(selected.len(), 1);
+       assert_eq!(selected[0].hash(), t1.hash());
+   }
+
+
+   #[test]
+   fn admission_checks_signer_nonce_and_balance() {
+       use super::MempoolError;
+       use crate::types::address::Address;
+       use crate::types::key_pair;
+       use crate::types::state::Account;
+       use crate::types::transaction::{sign, signed_transfer};
+
+       let key = key_pair::random();
+       let signed = |nonce: u32, value: u64| signed_transfer(&key, nonce, value, 1);
+       let sender = signed(1, 0).transaction.sender;
+       let account = Account { nonce: 1, balance: 100 };
+       let mut mempool = super::Mempool::new();
+
+       let valid = signed(2, 49);
+       assert_eq!(mempool.admit(&valid, &account), Ok(()));
+       assert_eq!(mempool.admit(&valid, &account), Err(MempoolError::AlreadyKnown(valid.hash())));
+       // a later nonce waits in the pool for the gap to fill
+       assert_eq!(mempool.admit(&signed(5, 1), &account), Ok(()));
+
+       let stale = mempool.admit(&signed(1, 1), &account).unwrap_err();
+       assert!(matches!(stale, MempoolError::StaleNonce { state_nonce: 1, found: 1, .. }));
+       assert!(!stale.is_misbehavior());
+       // the pooled transactions already spend 52 of the 100
+       let expensive = mempool.admit(&signed(3, 48), &account).unwrap_err();
+       assert!(matches!(expensive, MempoolError::InsufficientBalance { balance: 48, cost: 49, .. }));
+       assert!(!expensive.is_misbehavior());
+       assert_eq!(mempool.admit(&signed(3, 47), &account), Ok(()));
+
+       // someone else's key cannot spend from this sender
+       let other: Address = [3; 20].into();
+       let mut forged = signed(2, 1);
+       forged.transaction.sender = other;
+       forged.signature = sign(&forged.transaction, &key).as_ref().to_vec();
+       let forged = mempool.admit(&forged, &account).unwrap_err();
+       assert_eq!(forged, MempoolError::WrongSender { sender: other, signer: sender });
+       assert!(forged.is_misbehavior());
+       let mut tampered = signed(3, 1);
+       tampered.transaction.value = 50;
+       assert_eq!(mempool.admit(&tampered, &account), Err(MempoolError::InvalidSignature));
+       assert_eq!(mempool.transaction_map.len(), 3);
+   }
+
+
+   #[test]
+   fn pool_stays_within_limits() {
+       use super::{Mempool, MempoolConfig, MempoolError, MempoolStats};
+       use crate::types::key_pair;
+       use crate::types::state::Account;
+       use crate::types::transaction::signed_transfer;
+       use std::time::Duration;
+
+       let (a, b, c) = (key_pair::random(), key_pair::random(), key_pair::random());
+       let account = Account { nonce: 0, balance: 1000 };
+       let config = MempoolConfig { max_count: 3, max_per_sender: 2, ..MempoolConfig::default() };
+       let mut mempool = Mempool::with_config(config.clone());
+
+       let a1 = signed_transfer(&a, 1, 10, 1);
+       let a2 = signed_transfer(&a, 2, 10, 1);
+       assert_eq!(mempool.admit(&a1, &account), Ok(()));
+       assert_eq!(mempool.admit(&a2, &account), Ok(()));
+       assert!(matches!(mempool.admit(&signed_transfer(&a, 3, 10, 9), &account), Err(MempoolError::SenderLimit { limit: 2, .. })));
+       assert_eq!(mempool.admit(&signed_transfer(&b, 1, 10, 5), &account), Ok(()));
+
+       // a better paying transaction pushes out the last one of the cheapest sender
+       assert_eq!(mempool.admit(&signed_transfer(&c, 1, 10, 10), &account), Ok(()));
+       assert!(mempool.transaction_map.contains_key(&a1.hash()));
+       assert!(!mempool.transaction_map.contains_key(&a2.hash()));
+       // while one paying less than everything pooled is turned away
+       assert_eq!(mempool.admit(&signed_transfer(&c, 2, 10, 0), &account), Err(MempoolError::PoolFull));
+       let stats = mempool.stats();
+       assert_eq!(
+           stats,
+           MempoolStats {
+               count: 3,
+               ready: 3,
+               future: 0,
+               bytes: stats.bytes,
+               evicted: 1,
+               expired: 0,
+               rejected_full: 1,
+               rejected_sender_limit: 1,
+               replaced: 0,
+           }
+       );
+       assert_eq!(stats.bytes, 3 * bincode::serialize(&a1).unwrap().len());
+
+       let mut mempool = Mempool::with_config(MempoolConfig { expiry: Duration::ZERO, ..config });
+       mempool.admit(&a1, &account).unwrap();
+       mempool.expire();
+       assert!(mempool.transaction_map.is_empty());
+       assert_eq!(mempool.stats().expired, 1);
+       assert_eq!(mempool.stats().bytes, 0);
+       // an expired transaction is still remembered as seen
+       assert_eq!(mempool.admit(&a1, &account), Err(MempoolError::AlreadyKnown(a1.hash())));
+   }
+
+
+   #[test]
+   fn consecutive_nonces_are_mined_together() {
+       use super::{Mempool, MempoolError};
+       use crate::types::key_pair;
+       use crate::types::state::{Account, State};
+       use crate::types::transaction::signed_transfer;
+
+       let key = key_pair::random();
+       let transfer = |nonce: u32, fee: u64| signed_transfer(&key, nonce, 10, fee);
+       let sender = transfer(1, 0).transaction.sender;
+       let account = Account { nonce: 0, balance: 1000 };
+       let mut mempool = Mempool::new();
+       // admitted out of order, with a gap before the fourth
+       for nonce in [2, 4, 1] {
+           mempool.admit(&transfer(nonce, 1), &account).unwrap();
+       }
+       // the same nonce again needs a higher fee to replace the pooled one
+       assert_eq!(
+           mempool.admit(&signed_transfer(&key, 2, 20, 1), &account),
+           Err(MempoolError::ReplacementUnderpriced { replaced: transfer(2, 1).hash(), required: 2, found: 1 })
+       );
+       let stats = mempool.stats();
+       assert_eq!((stats.ready, stats.future), (2, 1));
+
+       let mut state = State::new();
+       state.credit(sender, 1000).unwrap();
+       let (selected, _) = mempool.select_transactions(&mut state.clone(), 4000);
+       let nonces: Vec<u32> = selected.iter().map(|tx| tx.transaction.account_nonce).collect();
+       assert_eq!(nonces, vec![1, 2]);
+
+       // filling the gap makes the rest ready
+       mempool.admit(&transfer(3, 1), &account).unwrap();
+       assert_eq!(mempool.stats().future, 0);
+       let (selected, _) = mempool.select_transactions(&mut state, 4000);
+       let nonces: Vec<u32> = selected.iter().map(|tx| tx.transaction.account_nonce).collect();
+       assert_eq!(nonces, vec![1, 2, 3, 4]);
+       assert_eq!(state.get(&sender).nonce, 4);
+   }
+
+
+   #[test]
+   fn higher_fee_replaces_a_pending_transaction() {
+       use super::{Mempool, MempoolConfig, MempoolError};
+       use crate::types::key_pair;
+       use crate::types::state::{Account, State};
+       use crate::types::transaction::signed_transfer;
+
+       let key = key_pair::random();
+       let transfer = |nonce: u32, fee: u64| signed_transfer(&key, nonce, 10, fee);
+       let sender = transfer(1, 0).transaction.sender;
+       let account = Account { nonce: 0, balance: 1000 };
+       // a replacement does not count against the sender's limit
+       let mut mempool = Mempool::with_config(MempoolConfig { max_per_sender: 1, ..MempoolConfig::default() });
+       let original = transfer(1, 20);
+       mempool.admit(&original, &account).unwrap();
+
+       // 10% more is required
+       assert_eq!(
+           mempool.admit(&transfer(1, 21), &account),
+           Err(MempoolError::ReplacementUnderpriced { replaced: original.hash(), required: 22, found: 21 })
+       );
+       let replacement = transfer(1, 22);
+       assert_eq!(mempool.conflict(&replacement), Some(original.hash()));
+       assert_eq!(mempool.admit(&replacement, &account), Ok(()));
+       assert_eq!(mempool.conflict(&replacement), None);
+       assert!(!mempool.transaction_map.contains_key(&original.hash()));
+       assert_eq!(mempool.stats().replaced, 1);
+       // the original cannot come back, so only one of them is ever mined
+       assert_eq!(mempool.admit(&original, &account), Err(MempoolError::AlreadyKnown(original.hash())));
+       // a replacement that does not fit in the pool leaves the pooled transaction alone
+       mempool.config.max_bytes = 0;
+       assert_eq!(mempool.admit(&transfer(1, 30), &account), Err(MempoolError::PoolFull));
+       assert!(mempool.transaction_map.contains_key(&replacement.hash()));
+       assert_eq!(mempool.stats().replaced, 1);
+
+       let mut state = State::new();
+       state.credit(sender, 1000).unwrap();
+       let (selected, fees) = mempool.select_transactions(&mut state, 4000);
+       assert_eq!(selected.len(), 1);
+       assert_eq!(selected[0].hash(), replacement.hash());
+       assert_eq!(fees, 22);
+   }
+
   
    /*
    #[timeout(60000)]
@@ -0,0 +1,83 @@
+use crate::types::hash::H256;
+
+use std::collections::HashSet;
+
+/// The most recently seen hashes, up to a fixed capacity.
+///
+/// Hashes are kept in two generations. Once the current one holds half the capacity, it becomes
+/// the previous one and the old previous generation is forgotten, so a hash is remembered for
+/// at least `capacity / 2` insertions after it was last added.
+#[derive(Debug, Clone)]
+pub struct RollingFilter {
+    current: HashSet<H256>,
+    previous: HashSet<H256>,
+    capacity: usize,
+}
+
+impl RollingFilter {
+    pub fn new(capacity: usize) -> Self {
+        Self { current: HashSet::new(), previous: HashSet::new(), capacity: capacity.max(2) }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.current.contains(hash) || self.previous.contains(hash)
+    }
+
+    pub fn insert(&mut self, hash: H256) {
+        if self.current.contains(&hash) {
+            return;
+        }
+        if self.current.len() >= self.capacity / 2 {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.previous.remove(&hash);
+        self.current.insert(hash);
+    }
+
+    pub fn remove(&mut self, hash: &H256) {
+        self.current.remove(hash);
+        self.previous.remove(hash);
+    }
+
+    /// Number of hashes remembered, at most the capacity
+    pub fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Extend<H256> for RollingFilter {
+    fn extend<I: IntoIterator<Item = H256>>(&mut self, hashes: I) {
+        for hash in hashes {
+            self.insert(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn oldest_hashes_are_forgotten() {
+        let mut filter = RollingFilter::new(4);
+        let hashes: Vec<H256> = (0..6).map(|_| generate_random_hash()).collect();
+        filter.extend(hashes[..4].iter().copied());
+        assert!(hashes[..4].iter().all(|hash| filter.contains(hash)));
+
+        // seeing a hash again keeps it around
+        filter.insert(hashes[0]);
+        filter.extend(hashes[4..].iter().copied());
+        assert!(filter.len() <= 4);
+        assert!(filter.contains(&hashes[0]));
+        assert!(!filter.contains(&hashes[1]));
+        assert!(filter.contains(&hashes[5]));
+
+        filter.remove(&hashes[5]);
+        assert!(!filter.contains(&hashes[5]));
+    }
+}
@@ -1,19 +1,20 @@
 use crossbeam::channel::Receiver;
-use log::{debug, info};
-use crate::types::block::Block;
+use log::{debug, error, info};
+use crate::types::block::{Block, BlockState};
 use crate::network::server::Handle as ServerHandle;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use crate::blockchain::Blockchain;
+use crate::blockchain::validation::validate_block;
 use crate::types::hash::Hashable;
 use crate::types::hash::H256;
-use crate::network::message::Message;
 
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
+    block_state_map: Arc<Mutex<BlockState>>,
 }
 
 impl Worker {
@@ -21,11 +22,13 @@ impl Worker {
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
         blockchain: Arc<Mutex<Blockchain>>,  // Add blockchain to the arguments
+        block_state_map: &Arc<Mutex<BlockState>>,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
             blockchain: Arc::clone(&blockchain),  // Clone the Arc for thread-safe access
+            block_state_map: Arc::clone(block_state_map),
         }
     }
 
@@ -61,12 +64,36 @@ impl Worker {
                 continue; // Skip inserting if the block is already present
             }
     
+            // Never relay a block our peers would reject
+            if let Err(e) = validate_block(&block, &blockchain) {
+                error!("Mined block {} is invalid: {}", block_hash, e);
+                continue;
+            }
+            let mut block_state = self.block_state_map.lock().unwrap();
+            let state = match block_state.block_state_map.get(&parent_hash).map(|parent| parent.apply_block(&block)) {
+                Some(Ok(state)) => state,
+                Some(Err(e)) => {
+                    error!("Mined block {} is invalid: {}", block_hash, e);
+                    continue;
+                }
+                None => {
+                    error!("No state found for the parent of mined block {}", block_hash);
+                    continue;
+                }
+            };
+    
             // Insert the block into the blockchain
-            blockchain.insert(&block);
+            if let Err(e) = blockchain.insert(&block) {
+                error!("Failed to persist mined block {}: {}", block_hash, e);
+                continue;
+            }
+            // the state is only recorded for a block that made it into the blockchain
+            block_state.insert(block_hash, blockchain.heights[&block_hash], state);
+            drop(block_state);
             info!("Block inserted: {}", block_hash);
     
             // Notify all miners to update their tip
-            self.server.broadcast(Message::NewBlockHashes(vec![block_hash]));
+            self.server.broadcast_block(&block);
             self.server.update();
         }
     }
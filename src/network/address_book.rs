@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most addresses the book remembers; the least recently seen are forgotten first
+pub const MAX_ADDRESSES: usize = 1000;
+/// Most addresses sent in, or accepted from, a single `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+/// Addresses not seen for this long, in seconds, are no longer gossiped or dialed
+pub const MAX_ADDRESS_AGE: u64 = 3 * 60 * 60;
+
+/// Current time in seconds since the Unix epoch, the unit of last-seen times
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs()
+}
+
+/// The P2P addresses of other nodes we know of, with the last time each was seen alive
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    /// listening address -> last-seen time
+    entries: HashMap<SocketAddr, u64>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self { entries: HashMap::new() }
+    }
+
+    /// Record that `addr` was seen alive at `last_seen`. Times in the future are clamped to now,
+    /// and an older time never overrides a newer one.
+    pub fn insert(&mut self, addr: SocketAddr, last_seen: u64) {
+        let last_seen = last_seen.min(now());
+        let entry = self.entries.entry(addr).or_insert(last_seen);
+        *entry = (*entry).max(last_seen);
+        if self.entries.len() > MAX_ADDRESSES {
+            if let Some(oldest) = self.entries.iter().min_by_key(|(_, seen)| **seen).map(|(addr, _)| *addr) {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.entries.remove(addr);
+    }
+
+    /// The `limit` most recently seen addresses that are not too old, most recent first
+    pub fn recent(&self, limit: usize) -> Vec<(SocketAddr, u64)> {
+        let cutoff = now().saturating_sub(MAX_ADDRESS_AGE);
+        let mut entries: Vec<(SocketAddr, u64)> = self.entries
+            .iter()
+            .filter(|(_, seen)| **seen >= cutoff)
+            .map(|(addr, seen)| (*addr, *seen))
+            .collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        entries.truncate(limit);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn keeps_latest_last_seen() {
+        let mut book = AddressBook::new();
+        let now = now();
+        book.insert(addr(1), now - 10);
+        book.insert(addr(1), now - 100);
+        book.insert(addr(2), now - 5);
+        book.insert(addr(3), now + 1000);
+        assert_eq!(book.recent(2), vec![(addr(3), now), (addr(2), now - 5)]);
+        assert_eq!(book.recent(10)[2], (addr(1), now - 10));
+
+        // stale addresses are not handed out
+        book.insert(addr(4), now - MAX_ADDRESS_AGE - 1);
+        assert_eq!(book.recent(10).len(), 3);
+    }
+
+    #[test]
+    fn forgets_oldest_when_full() {
+        let mut book = AddressBook::new();
+        let now = now();
+        for port in 0..MAX_ADDRESSES as u16 {
+            book.insert(addr(port + 1), now - 1);
+        }
+        book.insert(addr(0), now - 2);
+        book.insert(addr(60000), now);
+        let all = book.recent(usize::MAX);
+        assert_eq!(all.len(), MAX_ADDRESSES);
+        assert!(all.iter().all(|(a, _)| *a != addr(0)));
+        assert_eq!(book.recent(1), vec![(addr(60000), now)]);
+    }
+}
@@ -0,0 +1,170 @@
+use super::address_book::now;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Misbehavior score at which a peer is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// Seconds it takes a misbehavior score to drop by one point, so that an occasional slip of an
+/// honest peer never adds up to a ban
+pub const SCORE_DECAY_SECS: u64 = 36;
+
+/// Something a peer did that an honest peer would not do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+    /// A frame that does not decode to a `Message`
+    MalformedMessage,
+    /// A block that fails validation
+    InvalidBlock,
+    /// A transaction that fails validation
+    InvalidTransaction,
+    /// Blocks or transactions we never asked for
+    UnsolicitedData,
+}
+
+impl Misbehavior {
+    /// How much this adds to the peer's misbehavior score
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::UnsolicitedData => 5,
+        }
+    }
+}
+
+impl std::fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Misbehavior::MalformedMessage => write!(f, "malformed message"),
+            Misbehavior::InvalidBlock => write!(f, "invalid block"),
+            Misbehavior::InvalidTransaction => write!(f, "invalid transaction"),
+            Misbehavior::UnsolicitedData => write!(f, "unsolicited data"),
+        }
+    }
+}
+
+/// Peers we refuse to talk to until their ban expires.
+///
+/// Bans are keyed by IP, so a banned peer cannot come back from another source port or by
+/// announcing another listening port.
+#[derive(Debug, Default)]
+pub struct BanList {
+    /// peer IP -> end of the ban, in seconds since the Unix epoch
+    bans: HashMap<IpAddr, u64>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self { bans: HashMap::new() }
+    }
+
+    /// Ban `addr` for `duration` from now, extending any shorter ban already in place
+    pub fn ban(&mut self, addr: IpAddr, duration: Duration) {
+        let until = now().saturating_add(duration.as_secs());
+        let entry = self.bans.entry(addr).or_insert(until);
+        *entry = (*entry).max(until);
+    }
+
+    /// Lift the ban on `addr`. Returns whether it was banned.
+    pub fn unban(&mut self, addr: &IpAddr) -> bool {
+        self.bans.remove(addr).is_some()
+    }
+
+    pub fn is_banned(&self, addr: &IpAddr) -> bool {
+        self.bans.get(addr).is_some_and(|until| *until > now())
+    }
+
+    /// Every ban still in force with its end time, forgetting expired ones
+    pub fn list(&mut self) -> Vec<(IpAddr, u64)> {
+        let now = now();
+        self.bans.retain(|_, until| *until > now);
+        let mut bans: Vec<(IpAddr, u64)> = self.bans.iter().map(|(addr, until)| (*addr, *until)).collect();
+        bans.sort();
+        bans
+    }
+}
+
+/// Misbehavior scores of the IPs peers connect from.
+///
+/// Like bans, scores are keyed by IP, so a peer does not start over by reconnecting, and a
+/// report that arrives after its connection closed still counts.
+#[derive(Debug, Default)]
+pub struct Scores {
+    /// peer IP -> score and when it was last updated, in seconds since the Unix epoch
+    scores: HashMap<IpAddr, (u32, u64)>,
+}
+
+impl Scores {
+    pub fn new() -> Self {
+        Self { scores: HashMap::new() }
+    }
+
+    /// Add `misbehavior` to the score of `addr`. Returns the new score.
+    pub fn add(&mut self, addr: IpAddr, misbehavior: Misbehavior) -> u32 {
+        self.add_at(addr, misbehavior, now())
+    }
+
+    fn add_at(&mut self, addr: IpAddr, misbehavior: Misbehavior, now: u64) -> u32 {
+        // forget scores that decayed away, so the map does not grow with every IP ever seen
+        self.scores.retain(|_, entry| decayed(*entry, now) > 0);
+        let entry = self.scores.entry(addr).or_insert((0, now));
+        let score = decayed(*entry, now).saturating_add(misbehavior.score());
+        *entry = (score, now);
+        score
+    }
+
+    /// Forget the score of `addr`, once it has been banned for it
+    pub fn clear(&mut self, addr: &IpAddr) {
+        self.scores.remove(addr);
+    }
+}
+
+/// What is left at `now` of a score set at `since`
+fn decayed((score, since): (u32, u64), now: u64) -> u32 {
+    let decay = now.saturating_sub(since) / SCORE_DECAY_SECS;
+    score.saturating_sub(decay.min(u32::MAX as u64) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_expire_and_can_be_lifted() {
+        let a = IpAddr::from([127, 0, 0, 1]);
+        let b = IpAddr::from([127, 0, 0, 2]);
+        let mut bans = BanList::new();
+        bans.ban(a, Duration::from_secs(60));
+        bans.ban(b, Duration::from_secs(0));
+        assert!(bans.is_banned(&a));
+        assert!(!bans.is_banned(&b));
+        // a shorter ban does not shorten an existing one
+        bans.ban(a, Duration::from_secs(1));
+        assert_eq!(bans.list(), vec![(a, now() + 60)]);
+
+        assert!(bans.unban(&a));
+        assert!(!bans.is_banned(&a));
+        assert!(!bans.unban(&a));
+    }
+
+    #[test]
+    fn scores_add_up_per_ip_and_decay() {
+        let a = IpAddr::from([127, 0, 0, 1]);
+        let b = IpAddr::from([127, 0, 0, 2]);
+        let mut scores = Scores::new();
+        assert_eq!(scores.add_at(a, Misbehavior::MalformedMessage, 1000), 20);
+        assert_eq!(scores.add_at(b, Misbehavior::UnsolicitedData, 1000), 5);
+        assert_eq!(scores.add_at(a, Misbehavior::MalformedMessage, 1000), 40);
+
+        // ten points decayed since the last report
+        assert_eq!(scores.add_at(a, Misbehavior::InvalidTransaction, 1000 + 10 * SCORE_DECAY_SECS), 40);
+        // `b` decayed to nothing and was forgotten
+        assert!(!scores.scores.contains_key(&b));
+
+        scores.clear(&a);
+        assert_eq!(scores.add_at(a, Misbehavior::UnsolicitedData, 2000), 5);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::miner::{Mempool, MAX_BLOCK_SIZE};
+use crate::types::block::{Block, Content, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::SignedTransaction;
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Most partially reconstructed blocks kept while their missing transactions are fetched; the
+/// oldest is dropped to make room for a new one
+pub const MAX_PENDING_BLOCKS: usize = 32;
+
+/// Serialized size of the smallest transaction, one with an empty signature and public key
+/// like a coinbase
+const MIN_TRANSACTION_SIZE: usize = 76;
+
+/// Most transactions a block we mine can hold: the coinbase plus as many of the smallest
+/// transactions as fit in our block size limit. That limit is the miner's, not a consensus
+/// rule, so a compact block claiming more is not an offense; it is just not allocated for, and
+/// the block is fetched whole instead.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1 + MAX_BLOCK_SIZE / MIN_TRANSACTION_SIZE;
+
+/// Short ID of the transaction `tx` in the block `block`: the first eight bytes of the hash of
+/// both. Salting with the block hash keeps a collision from repeating in every block.
+pub fn short_id(block: &H256, tx: &H256) -> u64 {
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(block.as_ref());
+    bytes[32..].copy_from_slice(tx.as_ref());
+    let digest = ring::digest::digest(&ring::digest::SHA256, &bytes);
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&digest.as_ref()[..8]);
+    u64::from_le_bytes(id)
+}
+
+/// A block as relayed to peers that likely hold most of its transactions already: the header,
+/// the transactions the peer cannot have (the coinbase), and short IDs for the rest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    /// Transactions sent in full, with their position in the block, in increasing position
+    pub prefilled: Vec<(u32, SignedTransaction)>,
+    /// Short IDs of the other transactions, in block order
+    pub short_ids: Vec<u64>,
+}
+
+impl Hashable for CompactBlock {
+    fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let hash = block.hash();
+        let mut prefilled = vec![];
+        let mut short_ids = vec![];
+        for (index, tx) in block.content.transactions.iter().enumerate() {
+            if tx.is_coinbase() {
+                prefilled.push((index as u32, tx.clone()));
+            } else {
+                short_ids.push(short_id(&hash, &tx.hash()));
+            }
+        }
+        Self { header: block.header.clone(), prefilled, short_ids }
+    }
+
+    /// Whether the block holds more transactions than `MAX_BLOCK_TRANSACTIONS`, too many to
+    /// reconstruct
+    pub fn is_oversized(&self) -> bool {
+        self.prefilled.len() + self.short_ids.len() > MAX_BLOCK_TRANSACTIONS
+    }
+
+    /// Fill in the transactions `mempool` has. Returns `None` if the block is oversized, or the
+    /// prefilled positions do not fit the block.
+    pub fn reconstruct(&self, mempool: &Mempool) -> Option<PartialBlock> {
+        if self.is_oversized() {
+            return None;
+        }
+        let count = self.prefilled.len() + self.short_ids.len();
+        let mut transactions: Vec<Option<SignedTransaction>> = vec![None; count];
+        let mut previous = None;
+        for (index, tx) in &self.prefilled {
+            if *index as usize >= count || previous >= Some(*index) {
+                return None;
+            }
+            transactions[*index as usize] = Some(tx.clone());
+            previous = Some(*index);
+        }
+
+        // mempool transactions by short ID; an ID two of them share is left for the peer to send
+        let hash = self.hash();
+        let mut candidates: HashMap<u64, Option<&SignedTransaction>> = HashMap::new();
+        for (tx_hash, tx) in mempool.transaction_map.iter() {
+            candidates
+                .entry(short_id(&hash, tx_hash))
+                .and_modify(|candidate| *candidate = None)
+                .or_insert(Some(tx));
+        }
+        let free = transactions.iter_mut().filter(|tx| tx.is_none());
+        for (slot, id) in free.zip(&self.short_ids) {
+            *slot = candidates.get(id).copied().flatten().cloned();
+        }
+        Some(PartialBlock { header: self.header.clone(), transactions })
+    }
+}
+
+/// A compact block with the transactions we had filled in
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    pub header: Header,
+    transactions: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Positions of the transactions still missing, in increasing order
+    pub fn missing(&self) -> Vec<u32> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Complete the block with `missing`, the transactions at the positions `missing()` returned.
+    /// Returns `None` if that is not the right number of transactions.
+    pub fn fill(self, missing: Vec<SignedTransaction>) -> Option<Block> {
+        if missing.len() != self.transactions.iter().filter(|tx| tx.is_none()).count() {
+            return None;
+        }
+        let mut missing = missing.into_iter();
+        let transactions = self.transactions
+            .into_iter()
+            .map(|tx| tx.or_else(|| missing.next()))
+            .collect::<Option<Vec<SignedTransaction>>>()?;
+        Some(Block { header: self.header, content: Content { transactions } })
+    }
+}
+
+/// Partial blocks waiting for a `BlockTransactions` reply, shared by every network worker
+#[derive(Debug, Default)]
+pub struct PendingBlocks {
+    blocks: HashMap<H256, (PartialBlock, Instant)>,
+}
+
+impl PendingBlocks {
+    pub fn new() -> Self {
+        Self { blocks: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, block: PartialBlock) {
+        if self.blocks.len() >= MAX_PENDING_BLOCKS {
+            if let Some(oldest) = self.blocks.iter().min_by_key(|(_, (_, added))| *added).map(|(hash, _)| *hash) {
+                self.blocks.remove(&oldest);
+            }
+        }
+        self.blocks.insert(block.header.hash(), (block, Instant::now()));
+    }
+
+    pub fn take(&mut self, hash: &H256) -> Option<PartialBlock> {
+        self.blocks.remove(hash).map(|(block, _)| block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::generate_random_transaction;
+
+    fn random_signed_transaction() -> SignedTransaction {
+        SignedTransaction {
+            transaction: generate_random_transaction(),
+            signature: vec![],
+            public_key: vec![],
+        }
+    }
+
+    #[test]
+    fn block_is_rebuilt_from_the_mempool() {
+        let mut block = generate_random_block(&H256::default());
+        let coinbase = SignedTransaction::coinbase([1; 20].into(), 50, 1);
+        let known = random_signed_transaction();
+        let unknown = random_signed_transaction();
+        block.content.transactions = vec![coinbase, known.clone(), unknown.clone()];
+        let mut mempool = Mempool::new();
+        mempool.insert(&known);
+        mempool.insert(&random_signed_transaction());
+
+        let compact = CompactBlock::new(&block);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.short_ids.len(), 2);
+        let partial = compact.reconstruct(&mempool).unwrap();
+        assert_eq!(partial.missing(), vec![2]);
+        assert!(partial.clone().fill(vec![]).is_none());
+        let rebuilt = partial.fill(vec![unknown]).unwrap();
+        let hashes = |block: &Block| -> Vec<H256> { block.content.transactions.iter().map(|tx| tx.hash()).collect() };
+        assert_eq!(hashes(&rebuilt), hashes(&block));
+
+        // prefilled positions past the end of the block are rejected
+        let mut broken = CompactBlock::new(&block);
+        broken.prefilled[0].0 = 3;
+        assert!(broken.reconstruct(&mempool).is_none());
+
+        // so is a block with more transactions than fit in one
+        let mut oversized = CompactBlock::new(&block);
+        oversized.short_ids = vec![0; MAX_BLOCK_TRANSACTIONS];
+        assert!(oversized.is_oversized());
+        assert!(oversized.reconstruct(&mempool).is_none());
+        oversized.short_ids.pop();
+        assert!(!oversized.is_oversized());
+        assert!(oversized.reconstruct(&mempool).is_some());
+    }
+
+    #[test]
+    fn coinbase_is_the_smallest_transaction() {
+        let coinbase = SignedTransaction::coinbase([1; 20].into(), 50, 1);
+        assert_eq!(bincode::serialize(&coinbase).unwrap().len(), MIN_TRANSACTION_SIZE);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::types::hash::H256;
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most headers sent in a single `Headers` message; a full message means there are more
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+/// Most blocks asked of a peer in a single `GetBlocks` during sync
+pub const BLOCKS_PER_REQUEST: usize = 16;
+/// Most blocks requested from a single peer and not received yet
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 32;
+/// How far past our tip, in blocks, downloads are scheduled
+pub const DOWNLOAD_WINDOW: usize = 1024;
+/// A block not received this long after it was requested is requested again, maybe elsewhere
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Which blocks of the best header chain are being downloaded from which peers.
+///
+/// Blocks are handed out in batches to every peer that served us headers, so bodies come in
+/// from several peers at once, and each peer gets its next batch as soon as it delivers.
+#[derive(Debug, Default)]
+pub struct BlockDownload {
+    /// block hash -> the peer it was requested from, and when
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+}
+
+/// How far along the initial block download is, as reported by the API
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SyncProgress {
+    /// Height of the best header chain
+    pub header_height: u64,
+    /// Height of the longest chain of downloaded blocks
+    pub block_height: u64,
+    pub blocks_in_flight: usize,
+    /// Number of peers blocks are being downloaded from
+    pub downloading_from: usize,
+    pub synced: bool,
+}
+
+impl BlockDownload {
+    pub fn new() -> Self {
+        Self { in_flight: HashMap::new() }
+    }
+
+    /// Pick up to `BLOCKS_PER_REQUEST` of the `missing` blocks, lowest first, to request from
+    /// `peer`. Blocks requested from anyone within `BLOCK_DOWNLOAD_TIMEOUT` are skipped.
+    pub fn assign(&mut self, peer: SocketAddr, missing: &[H256]) -> Vec<H256> {
+        let now = Instant::now();
+        self.in_flight.retain(|_, (_, requested)| now.duration_since(*requested) < BLOCK_DOWNLOAD_TIMEOUT);
+        let busy = self.in_flight.values().filter(|(from, _)| *from == peer).count();
+        let batch: Vec<H256> = missing
+            .iter()
+            .filter(|hash| !self.in_flight.contains_key(*hash))
+            .take(BLOCKS_PER_REQUEST.min(MAX_BLOCKS_IN_FLIGHT_PER_PEER.saturating_sub(busy)))
+            .copied()
+            .collect();
+        for hash in &batch {
+            self.in_flight.insert(*hash, (peer, now));
+        }
+        batch
+    }
+
+    /// A block arrived, whoever it was requested from
+    pub fn received(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+    }
+
+    pub fn progress(&self, header_height: u64, block_height: u64) -> SyncProgress {
+        let mut peers: Vec<&SocketAddr> = self.in_flight.values().map(|(peer, _)| peer).collect();
+        peers.sort();
+        peers.dedup();
+        SyncProgress {
+            header_height,
+            block_height,
+            blocks_in_flight: self.in_flight.len(),
+            downloading_from: peers.len(),
+            synced: block_height >= header_height,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn batches_go_to_different_peers() {
+        let a = SocketAddr::from(([127, 0, 0, 1], 1));
+        let b = SocketAddr::from(([127, 0, 0, 1], 2));
+        let missing: Vec<H256> = (0..100).map(|_| generate_random_hash()).collect();
+        let mut download = BlockDownload::new();
+
+        let first = download.assign(a, &missing);
+        let second = download.assign(b, &missing);
+        assert_eq!(first, missing[..BLOCKS_PER_REQUEST]);
+        assert_eq!(second, missing[BLOCKS_PER_REQUEST..2 * BLOCKS_PER_REQUEST]);
+        assert_eq!(download.progress(100, 0).downloading_from, 2);
+
+        // a peer never has more than its share in flight
+        download.assign(a, &missing);
+        assert!(download.assign(a, &missing).is_empty());
+        for hash in &first {
+            download.received(hash);
+        }
+        assert_eq!(download.assign(a, &missing).len(), BLOCKS_PER_REQUEST);
+        assert_eq!(download.progress(100, 0).blocks_in_flight, 3 * BLOCKS_PER_REQUEST);
+    }
+}
@@ -0,0 +1,300 @@
+use super::message::{Message, Version, PROTOCOL_VERSION};
+use super::noise::{self, Session};
+use crate::types::address::Address;
+use crate::types::hash::H256;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::signature::Ed25519KeyPair;
+use std::io;
+use std::time::Duration;
+
+/// Oldest wire format version this node can still talk to: the first whose `Version` we decode
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+/// Index bincode tags `Message::Version` with, the first variant of `Message`
+const VERSION_VARIANT: u32 = 0;
+/// Service bit of a full node that stores the whole blockchain and serves blocks
+pub const NODE_NETWORK: u64 = 1;
+/// How long a peer has to complete the handshake before it is disconnected
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a handshake did not complete
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    /// The peer did not finish the handshake within `HANDSHAKE_TIMEOUT`
+    Timeout,
+    /// The peer sent something other than the handshake message we were waiting for
+    UnexpectedMessage,
+    /// The peer speaks a wire format version we do not support
+    IncompatibleVersion(u32),
+    /// The peer's blockchain starts from a different genesis block
+    GenesisMismatch { ours: H256, theirs: H256 },
+    /// The peer announced our own nonce, so we connected to ourselves
+    SelfConnection,
+    /// The peer did not complete the encrypted handshake or prove it holds its identity key
+    Unauthenticated,
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "{}", e),
+            HandshakeError::Timeout => write!(f, "handshake timed out"),
+            HandshakeError::UnexpectedMessage => write!(f, "unexpected message during handshake"),
+            HandshakeError::IncompatibleVersion(version) => {
+                write!(f, "incompatible protocol version {}", version)
+            }
+            HandshakeError::GenesisMismatch { ours, theirs } => {
+                write!(f, "peer genesis {} does not match ours {}", theirs, ours)
+            }
+            HandshakeError::SelfConnection => write!(f, "connected to ourselves"),
+            HandshakeError::Unauthenticated => write!(f, "peer failed the encrypted handshake"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+/// Rejections of an incompatible peer map to `InvalidData`, so callers can tell them from
+/// connection failures that are worth retrying
+impl From<HandshakeError> for io::Error {
+    fn from(e: HandshakeError) -> Self {
+        match e {
+            HandshakeError::Io(e) => e,
+            HandshakeError::Timeout => io::Error::new(io::ErrorKind::TimedOut, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Decide whether we can talk to the peer that sent `remote`
+pub fn check(local: &Version, remote: &Version) -> Result<(), HandshakeError> {
+    if remote.nonce == local.nonce {
+        return Err(HandshakeError::SelfConnection);
+    }
+    if remote.version < MIN_PROTOCOL_VERSION {
+        return Err(HandshakeError::IncompatibleVersion(remote.version));
+    }
+    if remote.genesis != local.genesis {
+        return Err(HandshakeError::GenesisMismatch { ours: local.genesis, theirs: remote.genesis });
+    }
+    Ok(())
+}
+
+/// Run the handshake on a freshly opened connection. With an `identity` key, the connection is
+/// encrypted first, see `noise::handshake`; both ends have to agree on that. Then both sides
+/// send their `Version`, check the other's, acknowledge it with a `VerAck` and wait for the
+/// peer's `VerAck`. Returns the peer's `Version` and the session that protects the rest of the
+/// connection once it may carry other messages.
+pub async fn perform<S>(
+    stream: S,
+    local: &Version,
+    identity: Option<&Ed25519KeyPair>,
+    initiator: bool,
+) -> Result<(Version, Session), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = exchange(stream, local, identity, initiator);
+    let timeout = async {
+        smol::Timer::after(HANDSHAKE_TIMEOUT).await;
+        Err(HandshakeError::Timeout)
+    };
+    smol::future::or(handshake, timeout).await
+}
+
+async fn exchange<S>(
+    mut stream: S,
+    local: &Version,
+    identity: Option<&Ed25519KeyPair>,
+    initiator: bool,
+) -> Result<(Version, Session), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut session = match identity {
+        Some(identity) => noise::handshake(&mut stream, identity, initiator).await?,
+        None => Session::plaintext(),
+    };
+    write_frame(&mut stream, &mut session, &Message::Version(local.clone())).await?;
+    let remote = read_version(&mut stream, &mut session).await?;
+    check(local, &remote)?;
+    write_frame(&mut stream, &mut session, &Message::VerAck).await?;
+    match read_frame(&mut stream, &mut session).await? {
+        Message::VerAck => Ok((remote, session)),
+        _ => Err(HandshakeError::UnexpectedMessage),
+    }
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, session: &mut Session, msg: &Message) -> io::Result<()> {
+    let payload = bincode::serialize(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_payload(stream, &session.sealer.seal(payload)).await
+}
+
+/// Read the peer's `Version`. The version number leading it is checked before the rest is
+/// decoded, since a peer on an older wire format may encode the rest differently.
+async fn read_version<S: AsyncRead + Unpin>(stream: &mut S, session: &mut Session) -> Result<Version, HandshakeError> {
+    let payload = session.opener.open(read_payload(stream).await?)?;
+    let (variant, version): (u32, u32) =
+        bincode::deserialize(&payload).map_err(|_| HandshakeError::UnexpectedMessage)?;
+    if variant != VERSION_VARIANT {
+        return Err(HandshakeError::UnexpectedMessage);
+    }
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(HandshakeError::IncompatibleVersion(version));
+    }
+    match bincode::deserialize(&payload) {
+        Ok(Message::Version(remote)) => Ok(remote),
+        Ok(_) => Err(HandshakeError::UnexpectedMessage),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e).into()),
+    }
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S, session: &mut Session) -> io::Result<Message> {
+    let payload = session.opener.open(read_payload(stream).await?)?;
+    bincode::deserialize(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Write one length-prefixed frame
+pub(super) async fn write_payload<S: AsyncWrite + Unpin>(stream: &mut S, payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    stream.write_all(payload).await?;
+    stream.flush().await
+}
+
+/// Read one length-prefixed frame of the handshake
+pub(super) async fn read_payload<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut size_buffer = [0u8; 4];
+    stream.read_exact(&mut size_buffer).await?;
+    let size = u32::from_be_bytes(size_buffer) as usize;
+    // handshake messages are tiny, anything large is not a handshake
+    if size > 1024 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "handshake frame too large"));
+    }
+    let mut payload = vec![0u8; size];
+    stream.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+/// The `Version` a node with the given chain, nonce, account and listening port announces
+pub fn local_version(
+    genesis: H256,
+    best_height: u64,
+    best_tip: H256,
+    nonce: u64,
+    address: Option<Address>,
+    listen_port: u16,
+) -> Version {
+    Version {
+        version: PROTOCOL_VERSION,
+        genesis,
+        best_height,
+        best_tip,
+        services: NODE_NETWORK,
+        nonce,
+        address,
+        listen_port,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+
+    /// Run a handshake between two nodes over a local socket, returning both results
+    fn handshake(
+        a: Version,
+        b: Version,
+    ) -> (Result<Version, HandshakeError>, Result<Version, HandshakeError>) {
+        handshake_with(a, None, b, None)
+    }
+
+    /// Run a handshake between two nodes that encrypt their connections if they have an identity
+    fn handshake_with(
+        a: Version,
+        a_identity: Option<&Ed25519KeyPair>,
+        b: Version,
+        b_identity: Option<&Ed25519KeyPair>,
+    ) -> (Result<Version, HandshakeError>, Result<Version, HandshakeError>) {
+        smol::block_on(async {
+            let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = listener.get_ref().local_addr().unwrap();
+            let accept = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                perform(&stream, &b, b_identity, false).await.map(|(version, _)| version)
+            };
+            let connect = async {
+                let stream = Async::<TcpStream>::connect(addr).await.unwrap();
+                perform(&stream, &a, a_identity, true).await.map(|(version, _)| version)
+            };
+            futures::join!(connect, accept)
+        })
+    }
+
+    #[test]
+    fn compatible_peers_learn_each_other() {
+        let genesis = generate_random_hash();
+        let a = local_version(genesis, 3, generate_random_hash(), 1, None, 0);
+        let b = local_version(genesis, 5, generate_random_hash(), 2, Some([2; 20].into()), 0);
+        let (a_result, b_result) = handshake(a.clone(), b.clone());
+        assert_eq!(a_result.unwrap(), b);
+        assert_eq!(b_result.unwrap(), a);
+    }
+
+    #[test]
+    fn mismatched_peers_are_rejected() {
+        let genesis = generate_random_hash();
+        let a = local_version(genesis, 0, genesis, 1, None, 0);
+
+        let other_genesis = local_version(generate_random_hash(), 0, genesis, 2, None, 0);
+        let (result, _) = handshake(a.clone(), other_genesis);
+        assert!(matches!(result, Err(HandshakeError::GenesisMismatch { .. })));
+
+        let mut old = local_version(genesis, 0, genesis, 2, None, 0);
+        old.version = MIN_PROTOCOL_VERSION - 1;
+        let (result, _) = handshake(a.clone(), old);
+        assert!(matches!(result, Err(HandshakeError::IncompatibleVersion(v)) if v == MIN_PROTOCOL_VERSION - 1));
+
+        let (result, _) = handshake(a.clone(), a);
+        assert!(matches!(result, Err(HandshakeError::SelfConnection)));
+    }
+
+    #[test]
+    fn older_version_is_rejected_before_decoding() {
+        // a peer on wire format 2 sent a `Version` without the fields added since
+        let mut payload = bincode::serialize(&(VERSION_VARIANT, 2u32)).unwrap();
+        payload.extend_from_slice(&[0; 8]);
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        let mut stream = futures::io::Cursor::new(frame);
+        let result = smol::block_on(read_version(&mut stream, &mut Session::plaintext()));
+        assert!(matches!(result, Err(HandshakeError::IncompatibleVersion(2))));
+    }
+
+    #[test]
+    fn encryption_has_to_match() {
+        let genesis = generate_random_hash();
+        let a = local_version(genesis, 0, genesis, 1, None, 0);
+        let b = local_version(genesis, 0, genesis, 2, None, 0);
+        let a_identity = crate::types::key_pair::random();
+        let b_identity = crate::types::key_pair::random();
+
+        let (a_result, b_result) = handshake_with(a.clone(), Some(&a_identity), b.clone(), Some(&b_identity));
+        assert_eq!(a_result.unwrap(), b);
+        assert_eq!(b_result.unwrap(), a);
+
+        let (a_result, b_result) = handshake_with(a.clone(), Some(&a_identity), b.clone(), None);
+        assert!(a_result.is_err() && b_result.is_err());
+        let (a_result, b_result) = handshake_with(a, None, b, Some(&b_identity));
+        assert!(a_result.is_err() && b_result.is_err());
+    }
+}
@@ -1,9 +1,53 @@
 use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use super::compact_block::CompactBlock;
+use crate::types::{address::Address, hash::H256, block::{Block, Header}, transaction::SignedTransaction};
+
+/// Version of the peer-to-peer wire format, bumped whenever the encoding of a message changes.
+///
+/// - 1: initial format
+/// - 2: transaction values and fees widened from `u32` to `u64`
+/// - 3: `Version` announces the node's account
+/// - 4: `Version` announces the node's listening port, address gossip with `GetAddr` and `Addr`
+/// - 5: headers-first sync with `GetHeaders` and `Headers`
+/// - 6: compact block relay with `CompactBlock`, `GetBlockTransactions` and `BlockTransactions`
+///
+/// `version` stays the first field of `Version`, and `Version` the first variant of `Message`,
+/// so that any peer's version can be read before the rest of its handshake is decoded.
+pub const PROTOCOL_VERSION: u32 = 6;
+/// First protocol version that understands `GetHeaders`
+pub const HEADERS_VERSION: u32 = 5;
+/// First protocol version that understands `CompactBlock`
+pub const COMPACT_BLOCKS_VERSION: u32 = 6;
+
+/// What a node tells a peer about itself when a connection opens
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Version {
+    /// Wire format version the node speaks, see `PROTOCOL_VERSION`
+    pub version: u32,
+    /// Hash of the node's genesis block; nodes on different genesis blocks cannot talk
+    pub genesis: H256,
+    pub best_height: u64,
+    pub best_tip: H256,
+    /// Bit set of the services the node offers, see `network::handshake`
+    pub services: u64,
+    /// Random per-process value that lets a node recognize a connection to itself
+    pub nonce: u64,
+    /// Account the node transacts with, so peers can address messages to it by account
+    pub address: Option<Address>,
+    /// Port the node accepts P2P connections on, so peers can gossip its address
+    pub listen_port: u16,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    Version(Version),
+    VerAck,
+    /// Ask a peer for the addresses of other nodes it knows
+    GetAddr,
+    /// Listening addresses of other nodes, with the last time each was seen (seconds since the Unix epoch)
+    Addr(Vec<(SocketAddr, u64)>),
     Ping(String),
     Pong(String),
     NewBlockHashes(Vec<H256>),
@@ -12,4 +56,15 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    /// Ask a peer for the headers of its longest chain that follow the first hash of this
+    /// block locator it knows, see `HeaderTree::locator`
+    GetHeaders(Vec<H256>),
+    /// Consecutive headers of the sender's longest chain, at most `MAX_HEADERS_PER_MESSAGE`
+    Headers(Vec<Header>),
+    /// A new block, with short IDs in place of the transactions the receiver likely has
+    CompactBlock(CompactBlock),
+    /// Ask a peer for the transactions at these positions of a block it sent compact
+    GetBlockTransactions(H256, Vec<u32>),
+    /// Transactions of a block, in the order they were asked for
+    BlockTransactions(H256, Vec<SignedTransaction>),
 }
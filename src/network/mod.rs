@@ -1,4 +1,12 @@
+pub mod address_book;
+pub mod ban;
+pub mod compact_block;
+pub mod download;
+pub mod handshake;
 pub mod message;
+pub mod noise;
+pub mod orphan_pool;
 pub mod peer;
+pub mod rate_limit;
 pub mod server;
 pub mod worker;
@@ -0,0 +1,227 @@
+use super::handshake::{read_payload, write_payload, HandshakeError};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, X25519};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use ring::{digest, hkdf, rand};
+use std::io;
+
+/// Mixed into every key and signature, so keys from another protocol are never accepted
+const PROTOCOL_NAME: &[u8] = b"bitcoin-p2p-XX_25519_Ed25519_ChaChaPoly_SHA256";
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+/// Our static key and the signature proving we hold it, as sent encrypted in the handshake
+const IDENTITY_LEN: usize = PUBLIC_KEY_LEN + SIGNATURE_LEN + aead::MAX_TAG_LEN;
+
+/// Encrypts the frames we send on a connection; on a plaintext connection it leaves them as is
+#[derive(Debug)]
+pub struct Sealer {
+    key: Option<LessSafeKey>,
+    nonce: u64,
+}
+
+/// Decrypts and authenticates the frames a peer sends; on a plaintext connection it leaves them
+/// as is
+#[derive(Debug)]
+pub struct Opener {
+    key: Option<LessSafeKey>,
+    nonce: u64,
+}
+
+/// How the frames of a connection are protected, and who is on the other end
+#[derive(Debug)]
+pub struct Session {
+    pub sealer: Sealer,
+    pub opener: Opener,
+    /// The Ed25519 identity key the peer proved it holds; `None` on a plaintext connection
+    pub remote_key: Option<Vec<u8>>,
+}
+
+/// Frames are numbered in each direction, and the number is the nonce, so a frame that is
+/// replayed, dropped or reordered fails to open
+fn nonce(counter: u64) -> Nonce {
+    let mut bytes = [0u8; aead::NONCE_LEN];
+    bytes[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::assume_unique_for_key(bytes)
+}
+
+impl Sealer {
+    pub fn seal(&mut self, mut payload: Vec<u8>) -> Vec<u8> {
+        if let Some(key) = &self.key {
+            key.seal_in_place_append_tag(nonce(self.nonce), Aad::empty(), &mut payload)
+                .expect("frame too large to encrypt");
+            self.nonce += 1;
+        }
+        payload
+    }
+}
+
+impl Opener {
+    pub fn open(&mut self, mut frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        if let Some(key) = &self.key {
+            let len = key
+                .open_in_place(nonce(self.nonce), Aad::empty(), &mut frame)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame failed authentication"))?
+                .len();
+            frame.truncate(len);
+            self.nonce += 1;
+        }
+        Ok(frame)
+    }
+}
+
+impl Session {
+    pub fn plaintext() -> Self {
+        Session {
+            sealer: Sealer { key: None, nonce: 0 },
+            opener: Opener { key: None, nonce: 0 },
+            remote_key: None,
+        }
+    }
+}
+
+fn derive_key(prk: &hkdf::Prk, label: &[u8]) -> LessSafeKey {
+    let info = [PROTOCOL_NAME, label];
+    let okm = prk.expand(&info, &CHACHA20_POLY1305).unwrap();
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+/// What a side signs to prove it holds its identity key: its role and the handshake transcript
+fn signed_transcript(role: &[u8], transcript: &[u8]) -> Vec<u8> {
+    [PROTOCOL_NAME, role, transcript].concat()
+}
+
+/// Our static key and a signature binding it to this handshake, encrypted under `key`
+fn seal_identity(key: &LessSafeKey, identity: &Ed25519KeyPair, role: &[u8], transcript: &[u8]) -> Vec<u8> {
+    let mut payload = identity.public_key().as_ref().to_vec();
+    payload.extend_from_slice(identity.sign(&signed_transcript(role, transcript)).as_ref());
+    key.seal_in_place_append_tag(nonce(0), Aad::from(transcript), &mut payload).unwrap();
+    payload
+}
+
+/// The peer's static key, if `sealed` decrypts under `key` and carries a valid signature of
+/// this handshake by that key
+fn open_identity(key: &LessSafeKey, mut sealed: Vec<u8>, role: &[u8], transcript: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+    if sealed.len() != IDENTITY_LEN {
+        return Err(HandshakeError::Unauthenticated);
+    }
+    let payload = key
+        .open_in_place(nonce(0), Aad::from(transcript), &mut sealed)
+        .map_err(|_| HandshakeError::Unauthenticated)?;
+    let (remote_key, remote_signature) = payload.split_at(PUBLIC_KEY_LEN);
+    signature::UnparsedPublicKey::new(&signature::ED25519, remote_key)
+        .verify(&signed_transcript(role, transcript), remote_signature)
+        .map_err(|_| HandshakeError::Unauthenticated)?;
+    Ok(remote_key.to_vec())
+}
+
+/// Agree on session keys with the peer and learn its static key, in three messages:
+///
+/// - initiator: its ephemeral X25519 key
+/// - responder: its ephemeral key, then its Ed25519 static key and a signature of the
+///   transcript, encrypted under a key derived from both ephemeral keys
+/// - initiator: its static key and signature, encrypted the same way
+///
+/// Like Noise XX, static keys are only sent encrypted, and each side proves it holds its key
+/// over a transcript that covers both ephemeral keys, so a recorded handshake cannot be replayed
+/// and a key cannot be claimed by someone who does not hold it. Nothing pins the keys up front;
+/// operators compare them against the keys they expect, see the `/network/peers` API.
+pub async fn handshake<S>(stream: &mut S, identity: &Ed25519KeyPair, initiator: bool) -> Result<Session, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let rng = rand::SystemRandom::new();
+    let unspecified = |_| io::Error::other("key agreement failed");
+    let ephemeral = EphemeralPrivateKey::generate(&X25519, &rng).map_err(unspecified)?;
+    let local_ephemeral = ephemeral.compute_public_key().map_err(unspecified)?.as_ref().to_vec();
+
+    let mut responder_message = vec![];
+    let remote_ephemeral = if initiator {
+        write_payload(stream, &local_ephemeral).await?;
+        responder_message = read_payload(stream).await?;
+        if responder_message.len() != PUBLIC_KEY_LEN + IDENTITY_LEN {
+            return Err(HandshakeError::Unauthenticated);
+        }
+        responder_message.drain(..PUBLIC_KEY_LEN).collect::<Vec<u8>>()
+    } else {
+        let remote_ephemeral = read_payload(stream).await?;
+        if remote_ephemeral.len() != PUBLIC_KEY_LEN {
+            return Err(HandshakeError::Unauthenticated);
+        }
+        remote_ephemeral
+    };
+    let (initiator_ephemeral, responder_ephemeral) = if initiator {
+        (&local_ephemeral, &remote_ephemeral)
+    } else {
+        (&remote_ephemeral, &local_ephemeral)
+    };
+    let transcript = digest::digest(&digest::SHA256, &[PROTOCOL_NAME, initiator_ephemeral, responder_ephemeral].concat());
+    let transcript = transcript.as_ref();
+    let prk = agreement::agree_ephemeral(
+        ephemeral,
+        &agreement::UnparsedPublicKey::new(&X25519, &remote_ephemeral),
+        HandshakeError::Unauthenticated,
+        |shared| Ok(hkdf::Salt::new(hkdf::HKDF_SHA256, transcript).extract(shared)),
+    )?;
+    let initiator_handshake = derive_key(&prk, b"initiator handshake");
+    let responder_handshake = derive_key(&prk, b"responder handshake");
+    let initiator_transport = derive_key(&prk, b"initiator transport");
+    let responder_transport = derive_key(&prk, b"responder transport");
+
+    let (remote_key, sealer, opener) = if initiator {
+        let remote_key = open_identity(&responder_handshake, responder_message, b"responder", transcript)?;
+        write_payload(stream, &seal_identity(&initiator_handshake, identity, b"initiator", transcript)).await?;
+        (remote_key, initiator_transport, responder_transport)
+    } else {
+        let mut message = local_ephemeral.clone();
+        message.extend(seal_identity(&responder_handshake, identity, b"responder", transcript));
+        write_payload(stream, &message).await?;
+        let remote_key = open_identity(&initiator_handshake, read_payload(stream).await?, b"initiator", transcript)?;
+        (remote_key, responder_transport, initiator_transport)
+    };
+    Ok(Session {
+        sealer: Sealer { key: Some(sealer), nonce: 0 },
+        opener: Opener { key: Some(opener), nonce: 0 },
+        remote_key: Some(remote_key),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn peers_learn_static_keys_and_share_a_channel() {
+        let a = key_pair::random();
+        let b = key_pair::random();
+        let (mut a_session, mut b_session) = smol::block_on(async {
+            let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = listener.get_ref().local_addr().unwrap();
+            let accept = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                handshake(&mut &stream, &b, false).await.unwrap()
+            };
+            let connect = async {
+                let stream = Async::<TcpStream>::connect(addr).await.unwrap();
+                handshake(&mut &stream, &a, true).await.unwrap()
+            };
+            futures::join!(connect, accept)
+        });
+        assert_eq!(a_session.remote_key.as_deref(), Some(b.public_key().as_ref()));
+        assert_eq!(b_session.remote_key.as_deref(), Some(a.public_key().as_ref()));
+
+        let sealed = a_session.sealer.seal(b"block".to_vec());
+        assert_ne!(sealed, b"block".to_vec());
+        let replayed = sealed.clone();
+        assert_eq!(b_session.opener.open(sealed).unwrap(), b"block".to_vec());
+        assert!(b_session.opener.open(replayed).is_err());
+
+        let mut tampered = b_session.sealer.seal(b"transaction".to_vec());
+        tampered[0] ^= 1;
+        assert!(a_session.opener.open(tampered).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Most orphan blocks kept; the oldest is evicted to make room for a new one
+pub const MAX_ORPHANS: usize = 100;
+/// Orphans whose parent has not shown up for this long are dropped
+pub const MAX_ORPHAN_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Blocks whose parent we do not have yet, kept until the parent is connected.
+/// Shared by every network worker, so an orphan and its parent may arrive in different
+/// messages, from different peers.
+#[derive(Debug, Default)]
+pub struct OrphanPool {
+    /// orphan hash -> the orphan and when it arrived
+    orphans: HashMap<H256, (Block, Instant)>,
+    /// parent hash -> hashes of the orphans waiting for it
+    by_parent: HashMap<H256, Vec<H256>>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self { orphans: HashMap::new(), by_parent: HashMap::new() }
+    }
+
+    /// Keep `block` until its parent is connected, dropping expired orphans and, if the pool is
+    /// full, the oldest one
+    pub fn insert(&mut self, block: Block) {
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            return;
+        }
+        let now = Instant::now();
+        let expired: Vec<H256> = self.orphans
+            .iter()
+            .filter(|(_, (_, arrived))| now.duration_since(*arrived) >= MAX_ORPHAN_AGE)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            if let Some(oldest) = self.orphans.iter().min_by_key(|(_, (_, arrived))| *arrived).map(|(hash, _)| *hash) {
+                self.remove(&oldest);
+            }
+        }
+        self.by_parent.entry(block.get_parent()).or_default().push(hash);
+        self.orphans.insert(hash, (block, now));
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    /// Remove and return the orphans waiting for `parent`, in the order they arrived
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        self.by_parent
+            .remove(parent)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|hash| self.orphans.remove(&hash))
+            .map(|(block, _)| block)
+            .collect()
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        if let Some((block, _)) = self.orphans.remove(hash) {
+            let parent = block.get_parent();
+            if let Some(siblings) = self.by_parent.get_mut(&parent) {
+                siblings.retain(|sibling| sibling != hash);
+                if siblings.is_empty() {
+                    self.by_parent.remove(&parent);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn children_are_found_by_parent() {
+        let parent = generate_random_block(&H256::default());
+        let child = generate_random_block(&parent.hash());
+        let sibling = generate_random_block(&parent.hash());
+        let grandchild = generate_random_block(&child.hash());
+        let mut pool = OrphanPool::new();
+        for block in [&child, &sibling, &grandchild] {
+            pool.insert(block.clone());
+        }
+        assert_eq!(pool.len(), 3);
+
+        let children: Vec<H256> = pool.take_children(&parent.hash()).iter().map(|b| b.hash()).collect();
+        assert_eq!(children, vec![child.hash(), sibling.hash()]);
+        assert!(pool.take_children(&parent.hash()).is_empty());
+        assert_eq!(pool.take_children(&child.hash()).len(), 1);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn oldest_orphan_is_evicted_when_full() {
+        // the pool does not check proof of work, so distinct nonces are enough
+        let template = generate_random_block(&H256::default());
+        let orphan = |nonce: u32| {
+            let mut block = template.clone();
+            block.header.nonce = nonce;
+            block
+        };
+        let mut pool = OrphanPool::new();
+        let first = orphan(0);
+        pool.insert(first.clone());
+        for nonce in 1..MAX_ORPHANS as u32 {
+            pool.insert(orphan(nonce));
+        }
+        assert!(pool.contains(&first.hash()));
+        let last = orphan(MAX_ORPHANS as u32);
+        pool.insert(last.clone());
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(!pool.contains(&first.hash()));
+        assert!(pool.contains(&last.hash()));
+    }
+}
@@ -1,57 +1,192 @@
 use super::message::Message;
-use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
+use crate::types::hash::H256;
+use log::{debug, trace};
+use smol::channel::{self, TrySendError};
 use smol::Async;
+use std::collections::HashSet;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+/// Most outstanding requested hashes remembered per peer
+const MAX_REQUESTED: usize = 10000;
+
+/// What to do with a message for a peer whose outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowPeer {
+    /// Drop the message and keep the peer
+    Drop,
+    /// Disconnect the peer
+    Disconnect,
+}
+
+/// Start tracking a connected peer whose outbound queue holds at most `queue_size` messages
 pub fn new(
-    stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
-    let (write_sender, write_receiver) = mpsc::unbounded();
+    stream: &Async<TcpStream>,
+    queue_size: usize,
+    slow_peer: SlowPeer,
+) -> std::io::Result<(channel::Receiver<Vec<u8>>, Handle)> {
+    let (write_sender, write_receiver) = channel::bounded(queue_size.max(1));
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        requested: Arc::new(Mutex::new(HashSet::new())),
+        socket: Some(Arc::new(stream.get_ref().try_clone()?)),
+        slow_peer,
+        stats: Arc::new(Stats::default()),
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
+/// Traffic on a connection so far, and how long the peer took to answer our last ping
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Traffic {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    /// `None` until the peer answered a ping
+    pub ping_rtt: Option<Duration>,
+}
+
+/// Counters the reader and writer of a connection update as frames go through
+#[derive(Debug, Default)]
+pub struct Stats {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    /// The last ping we sent and when, until the peer answers it
+    ping: Mutex<Option<(String, Instant)>>,
+    ping_rtt: Mutex<Option<Duration>>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
-    write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    write_queue: channel::Sender<Vec<u8>>,
+    /// Blocks and transactions we asked this peer for and have not received yet; a block whose
+    /// missing transactions we asked for is recorded by its hash
+    requested: Arc<Mutex<HashSet<H256>>>,
+    /// The connection, to shut it down when the peer is disconnected
+    socket: Option<Arc<TcpStream>>,
+    slow_peer: SlowPeer,
+    stats: Arc<Stats>,
+}
+
+impl Stats {
+    /// Record a frame of `bytes` bytes, header included, read from the peer
+    pub fn received(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a frame of `bytes` bytes, header included, written to the peer
+    pub fn sent(&self, bytes: u64) {
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver {
-    r: mpsc::UnboundedReceiver<Vec<u8>>
+    r: channel::Receiver<Vec<u8>>
 }
 
 impl Handle {
     pub fn write(&mut self, msg: Message) {
-        let buffer = bincode::serialize(&msg).unwrap();
-        smol::block_on(async move {
-            if self.write_queue.send(buffer).await.is_err() {
-                trace!("Trying to send to disconnected peer");
+        let hashes = match &msg {
+            Message::GetBlocks(hashes) | Message::GetTransactions(hashes) => hashes.as_slice(),
+            Message::GetBlockTransactions(hash, _) => std::slice::from_ref(hash),
+            _ => &[],
+        };
+        if !hashes.is_empty() {
+            let mut requested = self.requested.lock().unwrap();
+            if requested.len() + hashes.len() > MAX_REQUESTED {
+                // a peer that never answers must not grow this without bound
+                requested.clear();
             }
-        });
+            requested.extend(hashes.iter().copied());
+        }
+        if let Message::Ping(nonce) = &msg {
+            *self.stats.ping.lock().unwrap() = Some((nonce.clone(), Instant::now()));
+        }
+        let buffer = bincode::serialize(&msg).unwrap();
+        match self.write_queue.try_send(buffer) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => match self.slow_peer {
+                SlowPeer::Drop => trace!("Dropping message to slow peer {}", self.addr),
+                SlowPeer::Disconnect => {
+                    debug!("Disconnecting slow peer {}", self.addr);
+                    self.close();
+                }
+            },
+            Err(TrySendError::Closed(_)) => trace!("Trying to send to disconnected peer"),
+        }
     }
 
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    /// Whether we asked this peer for every one of `hashes`, forgetting them as requested
+    pub fn solicited(&self, hashes: &[H256]) -> bool {
+        let mut requested = self.requested.lock().unwrap();
+        hashes.iter().filter(|hash| !requested.remove(*hash)).count() == 0
+    }
+
+    /// The counters of this connection, for its reader and writer to update
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Record the peer's answer to a ping; a pong that does not echo our last ping is ignored
+    pub fn pong(&self, nonce: &str) {
+        let mut ping = self.stats.ping.lock().unwrap();
+        if let Some((sent_nonce, sent_at)) = ping.as_ref() {
+            if sent_nonce == nonce {
+                *self.stats.ping_rtt.lock().unwrap() = Some(sent_at.elapsed());
+                *ping = None;
+            }
+        }
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        Traffic {
+            bytes_in: self.stats.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.stats.bytes_out.load(Ordering::Relaxed),
+            messages_in: self.stats.messages_in.load(Ordering::Relaxed),
+            messages_out: self.stats.messages_out.load(Ordering::Relaxed),
+            ping_rtt: *self.stats.ping_rtt.lock().unwrap(),
+        }
+    }
+
+    /// Disconnect this peer; its reader and writer tasks finish and the peer is dropped
+    pub fn close(&self) {
+        self.write_queue.close();
+        if let Some(socket) = &self.socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
-        let (s,r) = mpsc::unbounded();
+        let (s,r) = channel::unbounded();
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
             write_queue: s,
+            requested: Arc::new(Mutex::new(HashSet::new())),
+            socket: None,
+            slow_peer: SlowPeer::Drop,
+            stats: Arc::new(Stats::default()),
         },
         TestReceiver {
             r
@@ -62,8 +197,58 @@ impl Handle {
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
-        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
+        let bytes = smol::block_on(self.r.recv()).unwrap();
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::AsyncReadExt;
+    use std::net::TcpListener;
+
+    /// Both ends of a local connection
+    fn connection() -> (Async<TcpStream>, Async<TcpStream>) {
+        smol::block_on(async {
+            let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = listener.get_ref().local_addr().unwrap();
+            let (accepted, connected) = futures::join!(listener.accept(), Async::<TcpStream>::connect(addr));
+            (accepted.unwrap().0, connected.unwrap())
+        })
+    }
+
+    #[test]
+    fn slow_peer_messages_are_dropped() {
+        let (ours, _theirs) = connection();
+        let (queue, mut handle) = new(&ours, 2, SlowPeer::Drop).unwrap();
+        for _ in 0..5 {
+            handle.write(Message::Ping(String::new()));
+        }
+        assert_eq!(queue.len(), 2);
+        assert!(!queue.is_closed());
+    }
+
+    #[test]
+    fn slow_peer_is_disconnected() {
+        let (ours, mut theirs) = connection();
+        let (queue, mut handle) = new(&ours, 2, SlowPeer::Disconnect).unwrap();
+        for _ in 0..3 {
+            handle.write(Message::Ping(String::new()));
+        }
+        assert!(queue.is_closed());
+        let mut buffer = [0u8; 1];
+        assert!(matches!(smol::block_on(theirs.read(&mut buffer)), Ok(0) | Err(_)));
+    }
+
+    #[test]
+    fn pong_to_the_last_ping_sets_rtt() {
+        let (mut handle, _receiver) = Handle::test_handle();
+        handle.write(Message::Ping("first".to_string()));
+        handle.write(Message::Ping("second".to_string()));
+        handle.pong("first");
+        assert_eq!(handle.traffic().ping_rtt, None);
+        handle.pong("second");
+        assert!(handle.traffic().ping_rtt.is_some());
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+
+/// How much unused allowance a limiter saves up for a burst
+const BURST: Duration = Duration::from_secs(1);
+
+/// Limits something to `rate` units per second, allowing bursts of up to one second's worth.
+///
+/// A single cost larger than a burst is still let through once the limiter is idle, and delays
+/// what comes after it instead, so no frame the transport accepts can stall forever.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Units per second; 0 means unlimited
+    rate: u64,
+    /// When everything taken so far is paid for at `rate`
+    paid_until: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self { rate, paid_until: Instant::now() }
+    }
+
+    /// Take `cost` units, returning how long the caller has to wait before going ahead
+    pub fn take(&mut self, cost: u64) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let duration = Duration::from_secs_f64(cost as f64 / self.rate as f64);
+        self.paid_until = self.paid_until.max(now) + duration;
+        let allowance = BURST.max(duration);
+        self.paid_until.saturating_duration_since(now).saturating_sub(allowance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_throttles() {
+        let mut limiter = RateLimiter::new(10);
+        for _ in 0..10 {
+            assert_eq!(limiter.take(1), Duration::ZERO);
+        }
+        let wait = limiter.take(1);
+        assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100));
+        let wait = limiter.take(1);
+        assert!(wait > Duration::from_millis(150) && wait <= Duration::from_millis(200));
+
+        // an oversized cost goes through at once but delays what follows
+        let mut limiter = RateLimiter::new(10);
+        assert_eq!(limiter.take(30), Duration::ZERO);
+        assert!(limiter.take(1) > Duration::from_secs(2));
+
+        assert_eq!(RateLimiter::new(0).take(u64::MAX), Duration::ZERO);
+    }
+}
@@ -1,42 +1,142 @@
+use crate::blockchain::Blockchain;
 use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::Hashable;
+use super::address_book::{self, AddressBook, MAX_ADDR_PER_MESSAGE};
+use super::ban::{BanList, Misbehavior, Scores, BAN_THRESHOLD};
+use super::compact_block::CompactBlock;
+use super::handshake;
 use super::peer;
+use super::rate_limit::RateLimiter;
 use super::message;
+use super::noise::Session;
 
 use async_dup::Arc as AsyncArc;
 use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
-use futures::{channel::oneshot, stream::StreamExt};
+use futures::channel::oneshot;
 use smol::{Async, Executor};
 use log::{debug, info, trace};
+use ring::signature::Ed25519KeyPair;
+use std::collections::{HashMap, HashSet};
 use std::net;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+/// How often the server checks whether it should dial more outbound peers
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the server waits before dialing the same address again
+const DIAL_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Settings of the P2P server
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Our account, announced in our handshakes
+    pub address: Option<Address>,
+    /// Number of outbound connections the server keeps open by dialing known addresses
+    pub target_outbound: usize,
+    /// How long a peer that crossed the misbehavior threshold stays banned
+    pub ban_duration: Duration,
+    /// Largest frame, in bytes, a peer may send; a peer announcing a larger one is disconnected
+    pub max_frame_size: usize,
+    /// Number of messages queued for a peer before it counts as slow
+    pub peer_queue_size: usize,
+    /// What happens to a slow peer's messages
+    pub slow_peer: peer::SlowPeer,
+    /// Messages per second read from each peer, 0 for no limit
+    pub max_inbound_messages: u64,
+    /// Bytes per second read from each peer, 0 for no limit
+    pub max_inbound_bytes: u64,
+    /// Identity key that encrypts our connections and authenticates us to peers; `None` keeps
+    /// connections in plaintext. Both ends of a connection have to agree.
+    pub identity: Option<Arc<Ed25519KeyPair>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: None,
+            target_outbound: 8,
+            ban_duration: Duration::from_secs(24 * 60 * 60),
+            max_frame_size: 32 * 1024 * 1024,
+            peer_queue_size: 1000,
+            slow_peer: peer::SlowPeer::Disconnect,
+            max_inbound_messages: 1000,
+            max_inbound_bytes: 16 * 1024 * 1024,
+            identity: None,
+        }
+    }
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    config: Config,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
     };
     let ctx = Context {
-        peers: std::collections::HashMap::new(),
+        peers: HashMap::new(),
         addr,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        blockchain: Arc::clone(blockchain),
+        nonce: rand::random(),
+        config,
+        address_peers: HashMap::new(),
+        address_book: AddressBook::new(),
+        dialing: HashSet::new(),
+        last_dial: HashMap::new(),
+        rejected: HashSet::new(),
+        bans: BanList::new(),
+        scores: Scores::new(),
     };
     Ok((ctx, handle))
 }
 
+/// A connected peer
+struct PeerEntry {
+    handle: peer::Handle,
+    direction: peer::Direction,
+    /// Address the peer accepts connections on, if it announced one
+    listen_addr: Option<std::net::SocketAddr>,
+    /// Wire format version the peer announced in its handshake
+    version: u32,
+    /// Identity key the peer proved it holds, on an encrypted connection
+    remote_key: Option<Vec<u8>>,
+    /// When the handshake completed, in seconds since the Unix epoch
+    connected_at: u64,
+}
+
 pub struct Context {
-    peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    peers: HashMap<std::net::SocketAddr, PeerEntry>,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    /// Random value announced in our handshakes, to detect connections to ourselves
+    nonce: u64,
+    config: Config,
+    /// Connected peers by the account they announced in their handshake
+    address_peers: HashMap<Address, HashSet<std::net::SocketAddr>>,
+    /// Listening addresses of other nodes, learned from handshakes and gossip
+    address_book: AddressBook,
+    /// Addresses we are connecting to but have not finished the handshake with
+    dialing: HashSet<std::net::SocketAddr>,
+    /// When each address was last dialed, to space out retries
+    last_dial: HashMap<std::net::SocketAddr, Instant>,
+    /// Addresses that failed the handshake (including our own), never dialed again
+    rejected: HashSet<std::net::SocketAddr>,
+    /// Peers we refuse to connect to or accept connections from
+    bans: BanList,
+    /// Misbehavior scores by IP, an IP is banned once its score reaches `BAN_THRESHOLD`
+    scores: Scores,
 }
 
 impl Context {
@@ -44,8 +144,9 @@ impl Context {
     pub fn start(self) -> std::io::Result<()> {
         // initialize the server socket
         let listener = Async::<net::TcpListener>::bind(self.addr)?;
-        info!("P2P server listening at {}", self.addr);
+        info!("P2P server listening at {} (protocol version {})", self.addr, message::PROTOCOL_VERSION);
         let control_chan = self.control_sender.clone();
+        let maintenance_chan = self.control_sender.clone();
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
@@ -57,6 +158,13 @@ impl Context {
             Self::listener_loop(listener, control_chan).await.unwrap();
         })
             .detach();
+        ex.spawn(async move {
+            // the control channel closes when the dispatcher exits
+            while maintenance_chan.send(ControlSignal::MaintainOutbound).await.is_ok() {
+                smol::Timer::after(MAINTENANCE_INTERVAL).await;
+            }
+        })
+            .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
         return Ok(());
     }
@@ -82,71 +190,368 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
-                    result_chan.send(handle).unwrap();
+                    if self.bans.is_banned(&addr.ip()) {
+                        let e = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "peer is banned");
+                        let _ = result_chan.send(Err(e));
+                        continue;
+                    }
+                    let connected = self.peers
+                        .iter()
+                        .any(|(peer_addr, entry)| *peer_addr == addr || entry.listen_addr == Some(addr));
+                    if connected || self.dialing.contains(&addr) {
+                        let e = std::io::Error::new(std::io::ErrorKind::AlreadyExists, "already connected to peer");
+                        let _ = result_chan.send(Err(e));
+                        continue;
+                    }
+                    self.dial(addr, Some(result_chan), &ex);
+                }
+                ControlSignal::DialFailed(addr, e, result_chan) => {
+                    trace!("Processing DialFailed({})", addr);
+                    self.dialing.remove(&addr);
+                    debug!("Failed to connect to peer {}: {}", addr, e);
+                    if e.kind() == std::io::ErrorKind::InvalidData {
+                        // the peer is incompatible with us, or is us
+                        self.address_book.remove(&addr);
+                        self.rejected.insert(addr);
+                    }
+                    if let Some(result_chan) = result_chan {
+                        let _ = result_chan.send(Err(e));
+                    }
+                }
+                ControlSignal::MaintainOutbound => {
+                    self.maintain_outbound(&ex);
+                }
+                ControlSignal::AddAddresses(addrs) => {
+                    trace!("Processing AddAddresses command");
+                    for (addr, last_seen) in addrs.into_iter().take(MAX_ADDR_PER_MESSAGE) {
+                        if !self.rejected.contains(&addr) && addr != self.addr {
+                            self.address_book.insert(addr, last_seen);
+                        }
+                    }
+                }
+                ControlSignal::GetAddresses(result_chan) => {
+                    trace!("Processing GetAddresses command");
+                    let _ = result_chan.send(self.address_book.recent(MAX_ADDR_PER_MESSAGE));
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
-                    for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                    for (_, entry) in self.peers.iter_mut() {
+                        entry.handle.write(msg.clone());
+                    }
+                }
+                ControlSignal::BroadcastBlock(block) => {
+                    trace!("Processing BroadcastBlock command");
+                    let compact = message::Message::CompactBlock(CompactBlock::new(&block));
+                    let hashes = message::Message::NewBlockHashes(vec![block.hash()]);
+                    for (_, entry) in self.peers.iter_mut() {
+                        if entry.version >= message::COMPACT_BLOCKS_VERSION {
+                            entry.handle.write(compact.clone());
+                        } else {
+                            entry.handle.write(hashes.clone());
+                        }
                     }
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    // refuse banned peers before spending a handshake on them
+                    match stream.get_ref().peer_addr() {
+                        Ok(addr) if self.bans.is_banned(&addr.ip()) => {
+                            debug!("Refusing banned peer {}", addr);
+                            let _ = stream.get_ref().shutdown(net::Shutdown::Both);
+                            continue;
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            debug!("Dropping incoming peer: {}", e);
+                            continue;
+                        }
+                    }
+                    let local = self.local_version();
+                    let identity = self.config.identity.clone();
+                    let control_chan = self.control_sender.clone();
+                    ex.spawn(async move {
+                        match handshake::perform(&stream, &local, identity.as_deref(), false).await {
+                            Ok((version, session)) => {
+                                let signal = ControlSignal::HandshakeDone(
+                                    stream,
+                                    peer::Direction::Incoming,
+                                    version,
+                                    Box::new(session),
+                                    None,
+                                );
+                                control_chan.send(signal).await.unwrap();
+                            }
+                            Err(e) => match stream.get_ref().peer_addr() {
+                                Ok(addr) => info!("Disconnecting incoming peer {}: {}", addr, e),
+                                Err(_) => info!("Disconnecting incoming peer: {}", e),
+                            },
+                        }
+                    })
+                        .detach();
+                }
+                ControlSignal::HandshakeDone(stream, direction, version, session, result_chan) => {
+                    trace!("Processing HandshakeDone command");
+                    let peer_addr = stream.get_ref().peer_addr().ok();
+                    if let Some(peer_addr) = peer_addr {
+                        self.dialing.remove(&peer_addr);
+                    }
+                    // an outgoing peer listens where we dialed it, an incoming one on the port it announced
+                    let listen_addr = match (direction, peer_addr) {
+                        (peer::Direction::Outgoing, Some(peer_addr)) => Some(peer_addr),
+                        (peer::Direction::Incoming, Some(peer_addr)) if version.listen_port != 0 => {
+                            Some(std::net::SocketAddr::new(peer_addr.ip(), version.listen_port))
+                        }
+                        _ => None,
+                    };
+                    // the ban may have come in while the handshake was running
+                    if peer_addr.is_some_and(|addr| self.bans.is_banned(&addr.ip())) {
+                        debug!("Disconnecting banned peer {:?}", peer_addr);
+                        let _ = stream.get_ref().shutdown(net::Shutdown::Both);
+                        if let Some(result_chan) = result_chan {
+                            let e = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "peer is banned");
+                            let _ = result_chan.send(Err(e));
+                        }
+                        continue;
+                    }
+                    let handle = self.register(stream, direction, listen_addr, version.version, *session, ex.clone()).await;
+                    if let Ok(handle) = &handle {
+                        info!(
+                            "Handshake with {} complete: protocol version {}, best height {}, best tip {}",
+                            handle.addr(), version.version, version.best_height, version.best_tip
+                        );
+                        if let Some(address) = version.address {
+                            self.address_peers.entry(address).or_default().insert(*handle.addr());
+                        }
+                        if let Some(listen_addr) = listen_addr {
+                            self.address_book.insert(listen_addr, address_book::now());
+                        }
+                        // learn about the rest of the network from the peers we chose
+                        if let peer::Direction::Outgoing = direction {
+                            handle.clone().write(message::Message::GetAddr);
+                        }
+                        // catch up on a longer chain headers first
+                        if version.version >= message::HEADERS_VERSION {
+                            let blockchain = self.blockchain.lock().unwrap();
+                            if version.best_height > blockchain.headers.best_height() {
+                                handle.clone().write(message::Message::GetHeaders(blockchain.headers.locator()));
+                            }
+                        }
+                    }
+                    match result_chan {
+                        Some(result_chan) => {
+                            let _ = result_chan.send(handle);
+                        }
+                        None => {
+                            if let Err(e) = handle {
+                                debug!("Failed to register incoming peer: {}", e);
+                            }
+                        }
+                    }
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
+                    if let Some(listen_addr) = self.peers.remove(&addr).and_then(|entry| entry.listen_addr) {
+                        self.address_book.insert(listen_addr, address_book::now());
+                    }
+                    self.address_peers.retain(|_, peers| {
+                        peers.remove(&addr);
+                        !peers.is_empty()
+                    });
                     info!("Peer {} disconnected", addr);
                 }
+                ControlSignal::Misbehaving(addr, misbehavior) => {
+                    trace!("Processing Misbehaving({})", addr);
+                    // scored by IP even if the peer already disconnected
+                    let score = self.scores.add(addr.ip(), misbehavior);
+                    info!("Peer {} misbehaved ({}), score {}", addr, misbehavior, score);
+                    if score >= BAN_THRESHOLD {
+                        info!("Banning peer {} for {:?}", addr.ip(), self.config.ban_duration);
+                        self.scores.clear(&addr.ip());
+                        self.ban(addr.ip(), self.config.ban_duration);
+                    }
+                }
+                ControlSignal::Ban(addr, duration) => {
+                    trace!("Processing Ban({})", addr);
+                    self.ban(addr, duration.unwrap_or(self.config.ban_duration));
+                }
+                ControlSignal::Unban(addr, result_chan) => {
+                    trace!("Processing Unban({})", addr);
+                    let _ = result_chan.send(self.bans.unban(&addr));
+                }
+                ControlSignal::GetBans(result_chan) => {
+                    trace!("Processing GetBans command");
+                    let _ = result_chan.send(self.bans.list());
+                }
+                ControlSignal::Disconnect(addr, result_chan) => {
+                    trace!("Processing Disconnect({})", addr);
+                    let mut found = false;
+                    for (peer_addr, entry) in self.peers.iter() {
+                        if *peer_addr == addr || entry.listen_addr == Some(addr) {
+                            entry.handle.close();
+                            found = true;
+                        }
+                    }
+                    let _ = result_chan.send(found);
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let mut peers: Vec<PeerInfo> = self.peers
+                        .iter()
+                        .map(|(addr, entry)| PeerInfo {
+                            addr: *addr,
+                            direction: entry.direction,
+                            static_key: entry.remote_key.clone(),
+                            connected_at: entry.connected_at,
+                            traffic: entry.handle.traffic(),
+                        })
+                        .collect();
+                    peers.sort_by_key(|peer| peer.addr);
+                    let _ = result_chan.send(peers);
+                }
                 ControlSignal::Update => {
                     trace!("Received Update signal, notifying miners to refresh state");
                     // Implement any additional update logic here if needed
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::SendToPeer(addr, msg, result_chan) => {
+                    trace!("Processing SendToPeer({})", addr);
+                    let result = match self.peers.get_mut(&addr) {
+                        Some(entry) => {
+                            entry.handle.write(msg);
+                            Ok(())
+                        }
+                        None => Err(SendError::UnknownPeer(addr)),
+                    };
+                    let _ = result_chan.send(result);
+                }
+                ControlSignal::SendToAddress(address, msg, result_chan) => {
+                    trace!("Processing SendToAddress({})", address);
+                    let addrs = self.address_peers.get(&address).cloned().unwrap_or_default();
+                    let result = if addrs.is_empty() {
+                        Err(SendError::UnknownAddress(address))
+                    } else {
+                        for addr in addrs {
+                            if let Some(entry) = self.peers.get_mut(&addr) {
+                                entry.handle.write(msg.clone());
+                            }
+                        }
+                        Ok(())
+                    };
+                    let _ = result_chan.send(result);
                 }
             }
         }
         return Ok(());
     }
 
-    /// Connect to a peer, and register this peer
-    async fn connect(
-        &mut self,
-        addr: &std::net::SocketAddr,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
-        debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
+    /// The `Version` we announce in handshakes, describing our current chain
+    fn local_version(&self) -> message::Version {
+        let blockchain = self.blockchain.lock().unwrap();
+        handshake::local_version(
+            blockchain.genesis_hash(),
+            blockchain.tip_height(),
+            blockchain.tip(),
+            self.nonce,
+            self.config.address,
+            self.addr.port(),
+        )
+    }
 
-        // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+    /// Ban `ip` and disconnect every peer connected from it
+    fn ban(&mut self, ip: std::net::IpAddr, duration: Duration) {
+        self.bans.ban(ip, duration);
+        for (peer_addr, entry) in self.peers.iter() {
+            if peer_addr.ip() == ip {
+                entry.handle.close();
+            }
+        }
     }
 
-    async fn accept(
+    /// Connect to `addr` and run the handshake in the background, so a slow peer does not hold
+    /// up the server. The outcome comes back as `HandshakeDone` or `DialFailed`.
+    fn dial(
         &mut self,
-        stream: Async<net::TcpStream>,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming, ex).await?;
-        Ok(())
+        addr: std::net::SocketAddr,
+        result_chan: Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+        ex: &Arc<Executor<'_>>,
+    ) {
+        self.dialing.insert(addr);
+        self.last_dial.insert(addr, Instant::now());
+        let local = self.local_version();
+        let identity = self.config.identity.clone();
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            debug!("Establishing connection to peer {}", addr);
+            let result = async {
+                let stream = Async::<std::net::TcpStream>::connect(addr).await?;
+                let (version, session) = handshake::perform(&stream, &local, identity.as_deref(), true).await?;
+                Ok::<_, std::io::Error>((stream, version, session))
+            }.await;
+            let signal = match result {
+                Ok((stream, version, session)) => {
+                    ControlSignal::HandshakeDone(stream, peer::Direction::Outgoing, version, Box::new(session), result_chan)
+                }
+                Err(e) => ControlSignal::DialFailed(addr, e, result_chan),
+            };
+            let _ = control_chan.send(signal).await;
+        })
+            .detach();
     }
 
+    /// Dial addresses from the address book until we have `target_outbound` outbound peers
+    fn maintain_outbound(&mut self, ex: &Arc<Executor<'_>>) {
+        let now = Instant::now();
+        self.last_dial.retain(|_, dialed| now.duration_since(*dialed) < DIAL_RETRY_INTERVAL);
+
+        let outbound = self.peers
+            .values()
+            .filter(|entry| matches!(entry.direction, peer::Direction::Outgoing))
+            .count() + self.dialing.len();
+        if outbound >= self.config.target_outbound {
+            return;
+        }
+        let connected: HashSet<std::net::SocketAddr> =
+            self.peers.values().filter_map(|entry| entry.listen_addr).collect();
+        let candidates: Vec<std::net::SocketAddr> = self.address_book
+            .recent(usize::MAX)
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .filter(|addr| {
+                *addr != self.addr
+                    && !self.bans.is_banned(&addr.ip())
+                    && !connected.contains(addr)
+                    && !self.dialing.contains(addr)
+                    && !self.last_dial.contains_key(addr)
+            })
+            .take(self.config.target_outbound - outbound)
+            .collect();
+        for addr in candidates {
+            self.dial(addr, None, ex);
+        }
+    }
+
+    /// Start exchanging messages with a peer that completed the handshake
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
+        listen_addr: Option<std::net::SocketAddr>,
+        version: u32,
+        session: Session,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (write_queue, handle) = peer::new(&stream, self.config.peer_queue_size, self.config.slow_peer)?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
+        let max_frame_size = self.config.max_frame_size;
+        let mut message_limiter = RateLimiter::new(self.config.max_inbound_messages);
+        let mut byte_limiter = RateLimiter::new(self.config.max_inbound_bytes);
+        let Session { mut sealer, mut opener, remote_key } = session;
+        let stats = handle.stats();
+        let reader_stats = Arc::clone(&stats);
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
@@ -164,6 +569,16 @@ impl Context {
                         break;
                     }
                 };
+                if msg_size as usize > max_frame_size {
+                    info!("Disconnecting peer {}: frame of {} bytes exceeds {}", addr, msg_size, max_frame_size);
+                    break;
+                }
+                // hold off reading a peer that sends too fast, so TCP slows it down
+                let wait = message_limiter.take(1).max(byte_limiter.take(msg_size as u64));
+                if !wait.is_zero() {
+                    trace!("Throttling peer {} for {:?}", addr, wait);
+                    smol::Timer::after(wait).await;
+                }
                 // then, read exactly msg_size bytes to get the whole message
                 if msg_buffer.len() < msg_size as usize {
                     msg_buffer.resize(msg_size as usize, 0);
@@ -173,7 +588,14 @@ impl Context {
                     .await
                 {
                     Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
+                        reader_stats.received(4 + msg_size as u64);
+                        let new_payload = match opener.open(msg_buffer[0..msg_size as usize].to_vec()) {
+                            Ok(payload) => payload,
+                            Err(e) => {
+                                info!("Disconnecting peer {}: {}", addr, e);
+                                break;
+                            }
+                        };
                         new_msg_chan
                             .send((new_payload, handle_copy.clone()))
                             .await
@@ -184,7 +606,8 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, make sure the writer stops too
+            handle_copy.close();
         })
             .detach();
 
@@ -193,7 +616,12 @@ impl Context {
         ex.spawn(async move {
             loop {
                 // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+                let new_msg = match write_queue.recv().await {
+                    Ok(msg) => msg,
+                    // the handle was closed to disconnect the peer
+                    Err(_) => break,
+                };
+                let new_msg = sealer.seal(new_msg);
 
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
@@ -217,6 +645,7 @@ impl Context {
                         break;
                     }
                 }
+                stats.sent((size_buffer.len() + new_msg.len()) as u64);
             }
             // the peer is disconnected
             control_chan
@@ -227,11 +656,51 @@ impl Context {
             .detach();
 
         // insert the peer handle so that we can broadcast to this guy later
-        self.peers.insert(addr, handle.clone());
+        let entry = PeerEntry {
+            handle: handle.clone(),
+            direction,
+            listen_addr,
+            version,
+            remote_key,
+            connected_at: address_book::now(),
+        };
+        self.peers.insert(addr, entry);
         Ok(handle)
     }
 }
 
+/// A connected peer, as reported to the API
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub direction: peer::Direction,
+    /// Identity key the peer proved it holds, on an encrypted connection
+    pub static_key: Option<Vec<u8>>,
+    /// When the handshake completed, in seconds since the Unix epoch
+    pub connected_at: u64,
+    pub traffic: peer::Traffic,
+}
+
+/// Why a message could not be sent to a specific peer
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendError {
+    /// No peer is connected at this address
+    UnknownPeer(std::net::SocketAddr),
+    /// No connected peer announced this account
+    UnknownAddress(Address),
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SendError::UnknownPeer(addr) => write!(f, "no peer connected at {}", addr),
+            SendError::UnknownAddress(address) => write!(f, "no connected peer announced account {}", address),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
@@ -243,11 +712,17 @@ pub struct TestReceiver{
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&self) -> Option<message::Message> {
-        let sig = smol::block_on(self.control_chan.recv()).unwrap();
-        match sig {
-            // in this test, only return broadcast msg
-            ControlSignal::BroadcastMessage(msg) => Some(msg),
-            _ => None,
+        loop {
+            let sig = smol::block_on(self.control_chan.recv()).unwrap();
+            match sig {
+                // in this test, only return broadcast msg
+                ControlSignal::BroadcastMessage(msg) => return Some(msg),
+                // a relayed block as peers without compact blocks see it
+                ControlSignal::BroadcastBlock(block) => return Some(message::Message::NewBlockHashes(vec![block.hash()])),
+                // misbehavior reports are not what these tests look at
+                ControlSignal::Misbehaving(..) => continue,
+                _ => return None,
+            }
         }
     }
 }
@@ -267,10 +742,75 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
-        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
+    /// Relay a new block: compact to peers that understand it, as `NewBlockHashes` to the rest
+    pub fn broadcast_block(&self, block: &Block) {
+        smol::block_on(self.control_chan.send(ControlSignal::BroadcastBlock(block.clone()))).unwrap();
+    }
+
+    /// Send a message to the connected peer at `addr` only
+    pub fn send_to_peer(&self, addr: std::net::SocketAddr, msg: message::Message) -> Result<(), SendError> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer(addr, msg, sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Send a message to every connected peer that announced the account `receiver`
+    pub fn send(&self, receiver: Address, msg: message::Message) -> Result<(), SendError> {
+        let (sender, result) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::SendToAddress(receiver, msg, sender))).unwrap();
+        smol::block_on(result).unwrap()
     }
     
+    /// Record addresses of other nodes, learned from gossip or given as seeds
+    pub fn add_addresses(&self, addrs: Vec<(std::net::SocketAddr, u64)>) {
+        smol::block_on(self.control_chan.send(ControlSignal::AddAddresses(addrs))).unwrap();
+    }
+
+    /// The most recently seen addresses in our address book, to answer `GetAddr`
+    pub fn known_addresses(&self) -> Vec<(std::net::SocketAddr, u64)> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetAddresses(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Report that the peer connected from `addr` misbehaved
+    pub fn report(&self, addr: std::net::SocketAddr, misbehavior: Misbehavior) {
+        smol::block_on(self.control_chan.send(ControlSignal::Misbehaving(addr, misbehavior))).unwrap();
+    }
+
+    /// Ban `ip` for `duration`, or for the configured ban duration, disconnecting its peers
+    pub fn ban(&self, ip: std::net::IpAddr, duration: Option<Duration>) {
+        smol::block_on(self.control_chan.send(ControlSignal::Ban(ip, duration))).unwrap();
+    }
+
+    /// Lift the ban on `ip`. Returns whether it was banned.
+    pub fn unban(&self, ip: std::net::IpAddr) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::Unban(ip, sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Banned IPs with the end of their bans, in seconds since the Unix epoch
+    pub fn bans(&self) -> Vec<(std::net::IpAddr, u64)> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetBans(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// The connected peers, by address
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Disconnect every peer connected from `addr` or listening on it; false if there is none
+    pub fn disconnect(&self, addr: std::net::SocketAddr) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::Disconnect(addr, sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
     pub fn update(&self) {
         // Send an Update signal to notify miners to pause and refresh their tip
         smol::block_on(self.control_chan.send(ControlSignal::Update)).unwrap();
@@ -291,8 +831,389 @@ enum ControlSignal {
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
     BroadcastMessage(message::Message),
+    BroadcastBlock(Block),
     GetNewPeer(Async<net::TcpStream>),
+    HandshakeDone(
+        Async<net::TcpStream>,
+        peer::Direction,
+        message::Version,
+        Box<Session>,
+        Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+    ),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    DialFailed(
+        std::net::SocketAddr,
+        std::io::Error,
+        Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+    ),
+    MaintainOutbound,
+    AddAddresses(Vec<(std::net::SocketAddr, u64)>),
+    GetAddresses(oneshot::Sender<Vec<(std::net::SocketAddr, u64)>>),
+    SendToPeer(std::net::SocketAddr, message::Message, oneshot::Sender<Result<(), SendError>>),
+    SendToAddress(Address, message::Message, oneshot::Sender<Result<(), SendError>>),
+    Misbehaving(std::net::SocketAddr, Misbehavior),
+    Ban(std::net::IpAddr, Option<Duration>),
+    Unban(std::net::IpAddr, oneshot::Sender<bool>),
+    GetBans(oneshot::Sender<Vec<(std::net::IpAddr, u64)>>),
+    GetPeers(oneshot::Sender<Vec<PeerInfo>>),
+    Disconnect(std::net::SocketAddr, oneshot::Sender<bool>),
     Update,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntest::timeout;
+
+    type Inbox = smol::channel::Receiver<(Vec<u8>, peer::Handle)>;
+
+    /// Start a node on a free local port
+    fn start_node(address: Option<Address>) -> (Handle, Inbox, net::SocketAddr) {
+        start_node_with(Config { address, ..Config::default() })
+    }
+
+    /// Start a node with the given settings on a free local port
+    fn start_node_with(config: Config) -> (Handle, Inbox, net::SocketAddr) {
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (msg_tx, msg_rx) = smol::channel::unbounded();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (ctx, handle) = new(addr, msg_tx, &blockchain, config).unwrap();
+        ctx.start().unwrap();
+        (handle, msg_rx, addr)
+    }
+
+    /// Start a node on a free local port with network workers answering its peers
+    fn start_full_node(seeds: Vec<net::SocketAddr>) -> (Handle, net::SocketAddr) {
+        let (handle, addr, _) = start_full_node_with(seeds, Blockchain::new());
+        (handle, addr)
+    }
+
+    /// Start a full node on a free local port that starts out with `blockchain`
+    fn start_full_node_with(
+        seeds: Vec<net::SocketAddr>,
+        blockchain: Blockchain,
+    ) -> (Handle, net::SocketAddr, Arc<Mutex<Blockchain>>) {
+        use crate::miner::Mempool;
+        use crate::network::download::BlockDownload;
+        use crate::network::worker::Worker;
+        use crate::types::block::BlockState;
+        use crate::types::state::State;
+
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (msg_tx, msg_rx) = smol::channel::unbounded();
+        let mut block_state = BlockState::new();
+        for hash in blockchain.all_blocks_in_longest_chain() {
+            // the test chains carry no transactions
+            block_state.block_state_map.insert(hash, State::new());
+        }
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let block_state = Arc::new(Mutex::new(block_state));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let download = Arc::new(Mutex::new(BlockDownload::new()));
+        let (ctx, handle) = new(addr, msg_tx, &blockchain, Config::default()).unwrap();
+        ctx.start().unwrap();
+        Worker::new(1, msg_rx, &handle, &blockchain, &mempool, &block_state, &download).start();
+        handle.add_addresses(seeds.into_iter().map(|seed| (seed, address_book::now())).collect());
+        (handle, addr, blockchain)
+    }
+
+    /// Connect to the node at `addr` as a bare peer that writes frames by hand
+    fn raw_peer(addr: net::SocketAddr) -> Async<net::TcpStream> {
+        smol::block_on(async {
+            let stream = Async::<net::TcpStream>::connect(addr).await.unwrap();
+            let genesis = Blockchain::new().genesis_hash();
+            let local = handshake::local_version(genesis, 0, genesis, rand::random(), None, 0);
+            handshake::perform(&stream, &local, None, true).await.unwrap();
+            stream
+        })
+    }
+
+    fn write_raw_frame(stream: &Async<net::TcpStream>, size: u32, payload: &[u8]) {
+        smol::block_on(async {
+            let mut stream = stream;
+            stream.write_all(&size.to_be_bytes()).await.unwrap();
+            stream.write_all(payload).await.unwrap();
+        })
+    }
+
+    /// The next message a peer sent, skipping the `GetAddr` every outbound peer starts with
+    fn next_message(inbox: &Inbox) -> message::Message {
+        loop {
+            let (bytes, _) = smol::block_on(inbox.recv()).unwrap();
+            match bincode::deserialize(&bytes).unwrap() {
+                message::Message::GetAddr => continue,
+                msg => return msg,
+            }
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn send_reaches_only_known_peers() {
+        let account: Address = [5; 20].into();
+        let (a, _, _) = start_node(None);
+        let (_, b_inbox, b_addr) = start_node(Some(account));
+        a.connect(b_addr).unwrap();
+
+        a.send_to_peer(b_addr, message::Message::Ping("by peer".to_string())).unwrap();
+        assert!(matches!(next_message(&b_inbox), message::Message::Ping(nonce) if nonce == "by peer"));
+        a.send(account, message::Message::Ping("by account".to_string())).unwrap();
+        assert!(matches!(next_message(&b_inbox), message::Message::Ping(nonce) if nonce == "by account"));
+
+        let unknown: net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert_eq!(
+            a.send_to_peer(unknown, message::Message::Ping(String::new())),
+            Err(SendError::UnknownPeer(unknown))
+        );
+        let other: Address = [6; 20].into();
+        assert_eq!(
+            a.send(other, message::Message::Ping(String::new())),
+            Err(SendError::UnknownAddress(other))
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn peers_report_traffic_and_can_be_disconnected() {
+        let (a, _) = start_full_node(vec![]);
+        let (_, b_inbox, b_addr) = start_node(None);
+        a.connect(b_addr).unwrap();
+        assert_eq!(a.connect(b_addr).unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+
+        a.send_to_peer(b_addr, message::Message::Ping("rtt".to_string())).unwrap();
+        loop {
+            let (bytes, mut peer) = smol::block_on(b_inbox.recv()).unwrap();
+            if let message::Message::Ping(nonce) = bincode::deserialize(&bytes).unwrap() {
+                peer.write(message::Message::Pong(nonce));
+                break;
+            }
+        }
+        while a.peers()[0].traffic.ping_rtt.is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let peers = a.peers();
+        assert_eq!(peers[0].addr, b_addr);
+        assert_eq!(peers[0].direction, peer::Direction::Outgoing);
+        assert!(peers[0].connected_at > 0);
+        // GetAddr and the ping went out, the pong came back
+        assert!(peers[0].traffic.messages_out >= 2);
+        assert_eq!(peers[0].traffic.messages_in, 1);
+        assert!(peers[0].traffic.bytes_in > 0);
+
+        assert!(a.disconnect(b_addr));
+        while !a.peers().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!a.disconnect(b_addr));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn nodes_find_each_other_through_a_seed() {
+        let (seed_handle, seed) = start_full_node(vec![]);
+        let (a_handle, a) = start_full_node(vec![seed]);
+        // the seed has to know `a` before `b` asks it for addresses
+        while !seed_handle.known_addresses().iter().any(|(addr, _)| *addr == a) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let (b, b_addr) = start_full_node(vec![seed]);
+
+        // `b` only knew the seed, but learns `a` from it. The seed may tell `a` about `b` as
+        // well, so either of them can be the one to dial the other.
+        loop {
+            let ping = message::Message::Ping(String::new());
+            if b.send_to_peer(a, ping.clone()).is_ok() || a_handle.send_to_peer(b_addr, ping).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(b.known_addresses().iter().any(|(addr, _)| *addr == a));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn misbehaving_peer_is_banned() {
+        let (a, _, a_addr) = start_node(None);
+        let (b, b_inbox, b_addr) = start_node(None);
+        a.connect(b_addr).unwrap();
+        a.send_to_peer(b_addr, message::Message::Ping(String::new())).unwrap();
+        // the connection `b` sees `a` on
+        let peer = loop {
+            let (bytes, peer) = smol::block_on(b_inbox.recv()).unwrap();
+            if let message::Message::Ping(_) = bincode::deserialize(&bytes).unwrap() {
+                break *peer.addr();
+            }
+        };
+
+        b.report(peer, Misbehavior::InvalidTransaction);
+        assert!(b.bans().is_empty());
+        b.report(peer, Misbehavior::InvalidBlock);
+        // `a` is banned by its IP and disconnected
+        assert!(b.bans().iter().any(|(ip, _)| *ip == a_addr.ip()));
+        // both ends drop the connection once it is shut down
+        while a.send_to_peer(b_addr, message::Message::Ping(String::new())).is_ok() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        while b.send_to_peer(peer, message::Message::Ping(String::new())) != Err(SendError::UnknownPeer(peer)) {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        // a fresh connection from another source port is refused before the handshake
+        assert!(a.connect(b_addr).is_err());
+        assert!(b.peers().is_empty());
+
+        assert!(b.unban(a_addr.ip()));
+        assert!(b.bans().is_empty());
+        a.connect(b_addr).unwrap();
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn misbehavior_counts_per_ip_after_disconnecting() {
+        let (b, _, _) = start_node(None);
+        let ip = std::net::IpAddr::from([127, 0, 0, 9]);
+        // reports against connections that are gone, from two source ports of the same IP
+        for port in [1000, 1001].iter() {
+            for _ in 0..5 {
+                b.report((ip, *port).into(), Misbehavior::InvalidTransaction);
+            }
+        }
+        assert!(b.bans().iter().any(|(banned, _)| *banned == ip));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn banned_address_is_not_dialed() {
+        let (a, _, _) = start_node(None);
+        let (_, _, b_addr) = start_node(None);
+        a.ban(b_addr.ip(), Some(Duration::from_secs(60)));
+        assert_eq!(a.connect(b_addr).unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        a.unban(b_addr.ip());
+        a.connect(b_addr).unwrap();
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn encrypted_peers_learn_each_others_keys() {
+        use crate::types::key_pair;
+        use ring::signature::KeyPair;
+
+        let a_identity = Arc::new(key_pair::random());
+        let b_identity = Arc::new(key_pair::random());
+        let (a, _, _) = start_node_with(Config { identity: Some(Arc::clone(&a_identity)), ..Config::default() });
+        let (b, b_inbox, b_addr) = start_node_with(Config { identity: Some(Arc::clone(&b_identity)), ..Config::default() });
+        a.connect(b_addr).unwrap();
+        a.send_to_peer(b_addr, message::Message::Ping("sealed".to_string())).unwrap();
+        assert!(matches!(next_message(&b_inbox), message::Message::Ping(nonce) if nonce == "sealed"));
+
+        let a_peers = a.peers();
+        assert_eq!(a_peers.len(), 1);
+        assert_eq!(a_peers[0].direction, peer::Direction::Outgoing);
+        assert_eq!(a_peers[0].static_key.as_deref(), Some(b_identity.public_key().as_ref()));
+        assert_eq!(b.peers()[0].static_key.as_deref(), Some(a_identity.public_key().as_ref()));
+
+        // a plaintext node cannot talk to an encrypted one
+        let (plain, _, _) = start_node(None);
+        assert!(plain.connect(b_addr).is_err());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn oversized_frame_disconnects_peer() {
+        let (_, inbox, addr) = start_node_with(Config { max_frame_size: 1024, ..Config::default() });
+        let stream = raw_peer(addr);
+        let ping = bincode::serialize(&message::Message::Ping(String::new())).unwrap();
+        write_raw_frame(&stream, ping.len() as u32, &ping);
+        assert!(matches!(next_message(&inbox), message::Message::Ping(_)));
+
+        // the node does not wait for, or allocate, the claimed payload
+        write_raw_frame(&stream, 1 << 30, &[]);
+        let mut buffer = [0u8; 1];
+        let read = smol::block_on(async {
+            let mut stream = &stream;
+            stream.read(&mut buffer).await
+        });
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn inbound_messages_are_rate_limited() {
+        let (_, inbox, addr) = start_node_with(Config { max_inbound_messages: 10, ..Config::default() });
+        let stream = raw_peer(addr);
+        let ping = bincode::serialize(&message::Message::Ping(String::new())).unwrap();
+        let start = Instant::now();
+        for _ in 0..25 {
+            write_raw_frame(&stream, ping.len() as u32, &ping);
+        }
+        for _ in 0..25 {
+            next_message(&inbox);
+        }
+        // a burst of 10, then 15 more at 10 per second
+        assert!(start.elapsed() >= Duration::from_millis(1400));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn new_node_syncs_headers_first() {
+        use crate::types::block::generate_random_block;
+        use crate::types::hash::Hashable;
+
+        // stay below the first retarget, the test blocks all carry the genesis difficulty
+        let mut chain = Blockchain::new();
+        for _ in 0..15 {
+            let block = generate_random_block(&chain.tip());
+            chain.insert(&block).unwrap();
+        }
+        let tip = chain.tip();
+        let (_, source, _) = start_full_node_with(vec![], chain);
+        let (_, _, blockchain) = start_full_node_with(vec![source], Blockchain::new());
+        while blockchain.lock().unwrap().tip() != tip {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.headers.best(), tip);
+        assert_eq!(blockchain.tip_height(), 15);
+        assert_eq!(blockchain.blocks[&tip].hash(), tip);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn blocks_are_relayed_compact_to_peers_that_understand_it() {
+        use crate::types::block::generate_random_block;
+
+        let (a, _, a_addr) = start_node(None);
+        let (_, b_inbox, b_addr) = start_node(None);
+        a.connect(b_addr).unwrap();
+        // a peer from before compact blocks
+        let old = smol::block_on(async {
+            let stream = Async::<net::TcpStream>::connect(a_addr).await.unwrap();
+            let genesis = Blockchain::new().genesis_hash();
+            let mut local = handshake::local_version(genesis, 0, genesis, rand::random(), None, 0);
+            local.version = message::HEADERS_VERSION;
+            handshake::perform(&stream, &local, None, true).await.unwrap();
+            stream
+        });
+        let old_addr = old.get_ref().local_addr().unwrap();
+        while a.send_to_peer(old_addr, message::Message::Ping(String::new())).is_err() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let block = generate_random_block(&Blockchain::new().genesis_hash());
+        a.broadcast_block(&block);
+        assert!(matches!(next_message(&b_inbox), message::Message::CompactBlock(compact) if compact.hash() == block.hash()));
+        let hashes = smol::block_on(async {
+            let mut old = &old;
+            loop {
+                let mut size = [0u8; 4];
+                old.read_exact(&mut size).await.unwrap();
+                let mut payload = vec![0u8; u32::from_be_bytes(size) as usize];
+                old.read_exact(&mut payload).await.unwrap();
+                if let message::Message::NewBlockHashes(hashes) = bincode::deserialize(&payload).unwrap() {
+                    break hashes;
+                }
+            }
+        });
+        assert_eq!(hashes, vec![block.hash()]);
+    }
+}
@@ -1,14 +1,20 @@
+use super::ban::Misbehavior;
+use super::compact_block::PendingBlocks;
+use super::download::{BlockDownload, DOWNLOAD_WINDOW, MAX_HEADERS_PER_MESSAGE};
 use super::message::Message;
+use super::orphan_pool::OrphanPool;
 use super::peer;
 use super::server::Handle as ServerHandle;
-use crate::miner::Mempool;
+use crate::miner::{admit_transaction, Mempool};
 use crate::types::block::{Block, BlockState};
 use crate::types::hash::{H256, Hashable};
-use crate::types::transaction::{SignedTransaction, verify};
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::SignedTransaction;
 use std::sync::{Arc, Mutex};
-use crate::blockchain::{Blockchain, DIFFICULTY};
+use crate::blockchain::Blockchain;
+use crate::blockchain::validation::{check_proof_of_work, validate_block_content, validate_coinbase, validate_header, BlockError};
 
-use log::{debug, warn, error};
+use log::{debug, info, warn, error};
 
 use std::thread;
 
@@ -23,17 +29,44 @@ pub struct Worker {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
-    block_state_map: Arc<Mutex<BlockState>>
+    block_state_map: Arc<Mutex<BlockState>>,
+    download: Arc<Mutex<BlockDownload>>,
+    /// Blocks waiting for their parent, shared by every worker thread
+    orphans: Arc<Mutex<OrphanPool>>,
+    /// Compact blocks waiting for their missing transactions, shared by every worker thread
+    pending_blocks: Arc<Mutex<PendingBlocks>>,
 }
 
-pub struct OrphanBuffer {
-    pub orphans: Vec<Block>
+/// Why a block whose parent we have could not be connected
+#[derive(Debug)]
+enum ConnectError {
+    /// The block is invalid on top of its parent
+    Invalid(BlockError),
+    /// The block is valid but could not be written to the block store
+    Store(std::io::Error),
 }
 
-impl OrphanBuffer {
-    pub fn new() -> Self {
-        return Self {
-            orphans: Vec::<Block>::new()
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectError::Invalid(e) => write!(f, "{}", e),
+            ConnectError::Store(e) => write!(f, "failed to persist block: {}", e),
+        }
+    }
+}
+
+impl From<BlockError> for ConnectError {
+    fn from(e: BlockError) -> Self {
+        ConnectError::Invalid(e)
+    }
+}
+
+impl ConnectError {
+    /// Whether the peer that sent the block is to blame
+    fn is_misbehavior(&self) -> bool {
+        match self {
+            ConnectError::Invalid(e) => e.is_permanent(),
+            ConnectError::Store(_) => false,
         }
     }
 }
@@ -45,7 +78,8 @@ impl Worker {
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
-        block_state_map: &Arc<Mutex<BlockState>>
+        block_state_map: &Arc<Mutex<BlockState>>,
+        download: &Arc<Mutex<BlockDownload>>,
     ) -> Self {
         Self {
             msg_chan: msg_src,
@@ -53,7 +87,10 @@ impl Worker {
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
             mempool: Arc::clone(mempool),
-            block_state_map: Arc::clone(block_state_map)
+            block_state_map: Arc::clone(block_state_map),
+            download: Arc::clone(download),
+            orphans: Arc::new(Mutex::new(OrphanPool::new())),
+            pending_blocks: Arc::new(Mutex::new(PendingBlocks::new())),
         }
     }
 
@@ -77,7 +114,14 @@ impl Worker {
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Malformed message from {}: {}", peer.addr(), e);
+                    self.server.report(*peer.addr(), Misbehavior::MalformedMessage);
+                    continue;
+                }
+            };
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -85,6 +129,7 @@ impl Worker {
                 }
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
+                    peer.pong(&nonce);
                 }
                 Message::NewBlockHashes(block_hashes) => {
                     let mut missing_blocks: Vec<H256> = Vec::<H256>::new();
@@ -100,12 +145,13 @@ impl Worker {
                 }
                 Message::NewTransactionHashes(tx_hashes) => {
                     let mut missing_txs: Vec<H256> = Vec::<H256>::new();
-                    let tx_set = self.mempool.lock().unwrap().transaction_set.clone();
+                    let mempool = self.mempool.lock().unwrap();
                     for tx in tx_hashes {
-                        if !tx_set.contains(&tx) {
+                        if !mempool.transaction_set.contains(&tx) {
                             missing_txs.push(tx);
                         }
                     }
+                    drop(mempool);
                     if missing_txs.len() != 0 {
                         peer.write(Message::GetTransactions(missing_txs));
                     }
@@ -137,140 +183,291 @@ impl Worker {
                     }
                 }
                 Message::Blocks(blocks) => {
-                    let mut broadcast_blocks: Vec<H256> = Vec::<H256>::new();
-                    let mut parent_blocks: Vec<H256> = Vec::<H256>::new();
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    // process_blocks represents blocks to process for orphan blocks
-                    let mut process_blocks = Vec::<Block>::new();
-                    let mut orphan_buffer: OrphanBuffer = OrphanBuffer::new();
-                    'block:for block in blocks {
-                        if !blockchain.blocks.contains_key(&block.hash()) {
-                            // Proof of Work
-                            if !(block.hash() <= DIFFICULTY.into()) {
-                                continue;
-                            }
-                            
-                            // TRANSACTION CHECKS
-                            // here only check for signature
-                            for transaction in &block.content.transactions {
-                                if !verify(&transaction.transaction, &transaction.public_key, &transaction.signature) {
-                                    continue 'block;
-                                }
-                            }
-                            
-                            // Parent Check/Orphan Block Check
-                            let parent_hash = block.get_parent();
-                            if blockchain.blocks.contains_key(&parent_hash) {
-                                let mut parent_state = self.block_state_map.lock().unwrap().block_state_map.get(&parent_hash).unwrap().clone();
-                                for tx in &block.content.transactions {
-                                    let sender = tx.transaction.sender;
-                                    let sender_state;
-                                    if parent_state.contains_key(&sender) {
-                                        sender_state = parent_state.get(&sender).unwrap().clone();
-                                    } else {
-                                        sender_state = (0, 0);
-                                    }
-                                    if (tx.transaction.value > sender_state.1) || (tx.transaction.account_nonce != sender_state.0 + 1) {
-                                        continue 'block;
-                                    }
-                                    // at this point the transaction is valid so update local state copy
-                                    parent_state.insert(tx.transaction.sender, (sender_state.0 + 1, sender_state.1 - &tx.transaction.value));
-                                    let receiver_state;
-                                    if parent_state.contains_key(&tx.transaction.receiver) {
-                                        receiver_state = parent_state.get(&tx.transaction.receiver).unwrap().clone();
-                                    } else {
-                                        receiver_state = (0, 0);
-                                    }
-                                    parent_state.insert(tx.transaction.receiver, (receiver_state.0, receiver_state.1 + &tx.transaction.value));
-                                }
-                                self.block_state_map.lock().unwrap().block_state_map.insert(block.hash(), parent_state);
-                                blockchain.insert(&block);
-                                let mut mempool = self.mempool.lock().unwrap();
-                                for tx in &block.content.transactions.clone() {
-                                    mempool.remove(&tx.hash());
-                                }
-                                broadcast_blocks.push(block.hash());
-                                // need to check for orphans
-                                process_blocks.push(block.clone());
-                            } else {
-                                orphan_buffer.orphans.push(block.clone());
-                                parent_blocks.push(parent_hash.clone());
-                            }
-
-                            // Orphan Buffer Check
-                            let mut keep_orphans = Vec::<Block>::new();
-                            while !process_blocks.is_empty() {
-                                let block = process_blocks.pop().unwrap();
-                                for orphan in orphan_buffer.orphans.clone() {
-                                    // block is parent, don't keep orphan
-                                    if orphan.get_parent() == block.hash() {
-                                        // here check balance and nonce
-                                        let mut parent_state = self.block_state_map.lock().unwrap().block_state_map.get(&block.hash()).unwrap().clone();
-                                        for tx in &orphan.content.transactions {
-                                            let sender = tx.transaction.sender;
-                                            let sender_state;
-                                            if parent_state.contains_key(&sender) {
-                                                sender_state = parent_state.get(&sender).unwrap().clone();
-                                            } else {
-                                                sender_state = (0, 0);
-                                            }
-                                            if (tx.transaction.value > sender_state.1) || (tx.transaction.account_nonce != sender_state.0 + 1) {
-                                                continue 'block;
-                                            }
-                                            // at this point the transaction is valid so update local state copy
-                                            parent_state.insert(tx.transaction.sender, (sender_state.0 + 1, sender_state.1 - &tx.transaction.value));
-                                            let receiver_state;
-                                            if parent_state.contains_key(&tx.transaction.receiver) {
-                                                receiver_state = parent_state.get(&tx.transaction.receiver).unwrap().clone();
-                                            } else {
-                                                receiver_state = (0, 0);
-                                            }
-                                            parent_state.insert(tx.transaction.receiver, (receiver_state.0, receiver_state.1 + &tx.transaction.value));
-                                        }
-                                        self.block_state_map.lock().unwrap().block_state_map.insert(orphan.hash(), parent_state);
-                                        blockchain.insert(&orphan);
-                                        let mut mempool = self.mempool.lock().unwrap();
-                                        for tx in block.content.transactions.clone() {
-                                            mempool.remove(&tx.hash());
-                                        }
-                                        broadcast_blocks.push(block.hash());
-                                        process_blocks.push(block.clone());
-                                    } 
-                                    // block isn't parent, keep orphan
-                                    else { keep_orphans.push(orphan); }
-                                }
-                                // update orphan buffer with kept orphans & reset keep_orpans
-                                orphan_buffer.orphans = keep_orphans.clone();
-                                keep_orphans = Vec::<Block>::new();
-                            }
-                        }
-                    }
-
-                    if parent_blocks.len() != 0 {
-                        peer.write(Message::GetBlocks(parent_blocks));
-                    }
-                    if broadcast_blocks.len() != 0 {
-                        self.server.broadcast(Message::NewBlockHashes(broadcast_blocks));
+                    let hashes: Vec<H256> = blocks.iter().map(|block| block.hash()).collect();
+                    if !peer.solicited(&hashes) {
+                        self.server.report(*peer.addr(), Misbehavior::UnsolicitedData);
                     }
+                    self.receive_blocks(&mut peer, blocks);
                 }
                 Message::Transactions(txs) => {
+                    let hashes: Vec<H256> = txs.iter().map(|tx| tx.hash()).collect();
+                    if !peer.solicited(&hashes) {
+                        self.server.report(*peer.addr(), Misbehavior::UnsolicitedData);
+                    }
+                    let mut invalid = false;
+                    // relay what we admitted, including replacements of pooled transactions
                     let mut broadcast_transactions: Vec<H256> = Vec::<H256>::new();
-                    let mut mempool = self.mempool.lock().unwrap();
                     for tx in txs {
-                        if verify(&tx.transaction, &tx.public_key, &tx.signature) {
-                            broadcast_transactions.push(tx.hash());
-                            mempool.insert(&tx);
+                        match admit_transaction(&tx, &self.blockchain, &self.block_state_map, &self.mempool) {
+                            Ok(()) => broadcast_transactions.push(tx.hash()),
+                            Err(e) => {
+                                debug!("Rejected transaction {} from {}: {}", tx.hash(), peer.addr(), e);
+                                invalid |= e.is_misbehavior();
+                            }
                         }
                     }
+                    if invalid {
+                        self.server.report(*peer.addr(), Misbehavior::InvalidTransaction);
+                    }
 
                     if broadcast_transactions.len() != 0 {
                         self.server.broadcast(Message::NewTransactionHashes(broadcast_transactions));
                     }
                 }
-                _ => unimplemented!(),
+                Message::GetAddr => {
+                    peer.write(Message::Addr(self.server.known_addresses()));
+                }
+                Message::Addr(addrs) => {
+                    debug!("Learned {} addresses from {}", addrs.len(), peer.addr());
+                    self.server.add_addresses(addrs);
+                }
+                Message::GetHeaders(locator) => {
+                    let headers = self.blockchain.lock().unwrap().headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+                    peer.write(Message::Headers(headers));
+                }
+                Message::Headers(headers) => {
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    let mut invalid = false;
+                    let mut added = 0;
+                    for header in &headers {
+                        if blockchain.headers.contains(&header.hash()) {
+                            continue;
+                        }
+                        if let Err(e) = validate_header(header, &blockchain) {
+                            debug!("Rejecting header {:?}: {}", header.hash(), e);
+                            invalid = e.is_permanent();
+                            break;
+                        }
+                        blockchain.headers.insert(header);
+                        added += 1;
+                    }
+                    let header_height = blockchain.headers.best_height();
+                    let locator = blockchain.headers.locator();
+                    drop(blockchain);
+                    info!("Received {} new headers from {}, best header height {}", added, peer.addr(), header_height);
+                    if invalid {
+                        self.server.report(*peer.addr(), Misbehavior::InvalidBlock);
+                    } else if headers.len() >= MAX_HEADERS_PER_MESSAGE {
+                        // a full message means the peer has more
+                        peer.write(Message::GetHeaders(locator));
+                    }
+                    self.request_blocks(&mut peer);
+                }
+                Message::CompactBlock(compact) => {
+                    let hash = compact.hash();
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let known = blockchain.blocks.contains_key(&hash);
+                    let parent_known = blockchain.blocks.contains_key(&compact.header.parent);
+                    drop(blockchain);
+                    if known || self.orphans.lock().unwrap().contains(&hash) {
+                        continue;
+                    }
+                    if let Err(e) = check_proof_of_work(&compact.header) {
+                        debug!("Rejecting compact block {:?}: {}", hash, e);
+                        self.server.report(*peer.addr(), Misbehavior::InvalidBlock);
+                        continue;
+                    }
+                    if !parent_known || compact.is_oversized() {
+                        // an orphan cannot be connected anyway, and a block bigger than ours may
+                        // still be valid; fetch either whole like an announced hash
+                        peer.write(Message::GetBlocks(vec![hash]));
+                        continue;
+                    }
+                    let partial = match compact.reconstruct(&self.mempool.lock().unwrap()) {
+                        Some(partial) => partial,
+                        None => {
+                            self.server.report(*peer.addr(), Misbehavior::MalformedMessage);
+                            continue;
+                        }
+                    };
+                    let missing = partial.missing();
+                    if missing.is_empty() {
+                        self.receive_reconstructed(&mut peer, partial.fill(vec![]).unwrap());
+                    } else {
+                        debug!("Compact block {:?} is missing {} transactions", hash, missing.len());
+                        self.pending_blocks.lock().unwrap().insert(partial);
+                        peer.write(Message::GetBlockTransactions(hash, missing));
+                    }
+                }
+                Message::GetBlockTransactions(hash, indexes) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let block = match blockchain.blocks.get(&hash) {
+                        Some(block) => block,
+                        None => continue,
+                    };
+                    let txs: Option<Vec<SignedTransaction>> = indexes
+                        .iter()
+                        .map(|index| block.content.transactions.get(*index as usize).cloned())
+                        .collect();
+                    drop(blockchain);
+                    match txs {
+                        Some(txs) => peer.write(Message::BlockTransactions(hash, txs)),
+                        None => self.server.report(*peer.addr(), Misbehavior::MalformedMessage),
+                    }
+                }
+                Message::BlockTransactions(hash, txs) => {
+                    if !peer.solicited(&[hash]) {
+                        self.server.report(*peer.addr(), Misbehavior::UnsolicitedData);
+                        continue;
+                    }
+                    // another peer may have completed the block already
+                    let partial = match self.pending_blocks.lock().unwrap().take(&hash) {
+                        Some(partial) => partial,
+                        None => continue,
+                    };
+                    match partial.fill(txs) {
+                        Some(block) => self.receive_reconstructed(&mut peer, block),
+                        None => {
+                            self.server.report(*peer.addr(), Misbehavior::MalformedMessage);
+                            peer.write(Message::GetBlocks(vec![hash]));
+                        }
+                    }
+                }
+                Message::Version(_) | Message::VerAck => {
+                    debug!("Ignoring handshake message from {} after the handshake", peer.addr());
+                }
             }
         }
     }
+
+    /// Connect `blocks` received from `peer`, keeping those whose parent is missing as orphans,
+    /// and relay the ones that connected
+    fn receive_blocks(&self, peer: &mut peer::Handle, blocks: Vec<Block>) {
+        let hashes: Vec<H256> = blocks.iter().map(|block| block.hash()).collect();
+        // the sender is reported once per message, however many blocks are invalid
+        let mut invalid = false;
+        let mut broadcast_blocks: Vec<H256> = Vec::<H256>::new();
+        let mut parent_blocks: Vec<H256> = Vec::<H256>::new();
+        let mut blockchain = self.blockchain.lock().unwrap();
+        for block in blocks {
+            if blockchain.blocks.contains_key(&block.hash()) {
+                continue;
+            }
+            // Proof of Work against the target the header claims, merkle root and
+            // signatures; the rest of the header is checked once the parent is known
+            if let Err(e) = check_proof_of_work(&block.header).and_then(|_| validate_block_content(&block)) {
+                debug!("Rejecting block {:?}: {}", block.hash(), e);
+                invalid = true;
+                continue;
+            }
+
+            let parent_hash = block.get_parent();
+            if !blockchain.blocks.contains_key(&parent_hash) {
+                // a parent on the header chain is fetched by the block download
+                if !blockchain.headers.contains(&parent_hash) {
+                    parent_blocks.push(parent_hash);
+                }
+                self.orphans.lock().unwrap().insert(block);
+                continue;
+            }
+            if let Err(e) = self.connect_block(&mut blockchain, &block) {
+                match e {
+                    ConnectError::Store(_) => error!("Dropping block {:?}: {}", block.hash(), e),
+                    ConnectError::Invalid(_) => debug!("Rejecting block {:?}: {}", block.hash(), e),
+                }
+                invalid |= e.is_misbehavior();
+                continue;
+            }
+            broadcast_blocks.push(block.hash());
+            broadcast_blocks.extend(self.connect_orphans(&mut blockchain, block.hash()));
+        }
+
+        // a single new block, most likely our new tip, is relayed compact where possible
+        let relay_block = match broadcast_blocks.as_slice() {
+            [hash] => Some(blockchain.blocks[hash].clone()),
+            _ => None,
+        };
+        drop(blockchain);
+        if invalid {
+            self.server.report(*peer.addr(), Misbehavior::InvalidBlock);
+        }
+        if parent_blocks.len() != 0 {
+            peer.write(Message::GetBlocks(parent_blocks));
+        }
+        if let Some(block) = relay_block {
+            self.server.broadcast_block(&block);
+        } else if broadcast_blocks.len() != 0 {
+            self.server.broadcast(Message::NewBlockHashes(broadcast_blocks));
+        }
+        // blocks that did not connect are requested again with the rest
+        let mut download = self.download.lock().unwrap();
+        for hash in &hashes {
+            download.received(hash);
+        }
+        drop(download);
+        self.request_blocks(peer);
+    }
+
+    /// Connect a block rebuilt from a compact block. If its transactions do not match the merkle
+    /// root, a short ID matched the wrong transaction and the whole block is fetched instead.
+    fn receive_reconstructed(&self, peer: &mut peer::Handle, block: Block) {
+        if MerkleTree::new(&block.content.transactions).root() != block.header.merkle_root {
+            debug!("Compact block {:?} did not rebuild, fetching it whole", block.hash());
+            peer.write(Message::GetBlocks(vec![block.hash()]));
+            return;
+        }
+        self.receive_blocks(peer, vec![block]);
+    }
+
+    /// Connect a block whose parent is in `blockchain` and whose content was validated: check
+    /// the header and coinbase against the parent, apply the transactions to the parent's state,
+    /// and drop the block's transactions from the mempool
+    fn connect_block(&self, blockchain: &mut Blockchain, block: &Block) -> Result<(), ConnectError> {
+        validate_header(&block.header, blockchain)?;
+        validate_coinbase(block, blockchain)?;
+        let parent_hash = block.get_parent();
+        let mut block_state = self.block_state_map.lock().unwrap();
+        // check balances and nonces against the parent's state
+        let parent_state = block_state.block_state_map.get(&parent_hash).ok_or(BlockError::UnknownParent(parent_hash))?;
+        let state = parent_state.apply_block(block).map_err(BlockError::from)?;
+        let height = blockchain.heights[&parent_hash] + 1;
+        // the state is only recorded for a block that made it into the blockchain
+        blockchain.insert(block).map_err(ConnectError::Store)?;
+        block_state.insert(block.hash(), height, state);
+        drop(block_state);
+        let mut mempool = self.mempool.lock().unwrap();
+        for tx in &block.content.transactions {
+            mempool.remove(&tx.hash());
+        }
+        Ok(())
+    }
+
+    /// Connect the orphans descending from the newly connected block `parent`, returning the
+    /// hashes of those that connected
+    fn connect_orphans(&self, blockchain: &mut Blockchain, parent: H256) -> Vec<H256> {
+        let mut connected = vec![];
+        let mut parents = vec![parent];
+        while let Some(parent) = parents.pop() {
+            let children = self.orphans.lock().unwrap().take_children(&parent);
+            for orphan in children {
+                match self.connect_block(blockchain, &orphan) {
+                    Ok(()) => {
+                        connected.push(orphan.hash());
+                        parents.push(orphan.hash());
+                    }
+                    // the orphan may have come from another peer, so nobody is blamed
+                    Err(e) => debug!("Rejecting orphan block {:?}: {}", orphan.hash(), e),
+                }
+            }
+        }
+        connected
+    }
+
+    /// Ask `peer` for the next blocks of the best header chain that we are missing, if any
+    fn request_blocks(&self, peer: &mut peer::Handle) {
+        let mut missing = self.blockchain.lock().unwrap().missing_blocks(DOWNLOAD_WINDOW);
+        // blocks waiting in the orphan pool arrived already
+        let orphans = self.orphans.lock().unwrap();
+        missing.retain(|hash| !orphans.contains(hash));
+        drop(orphans);
+        if missing.is_empty() {
+            return;
+        }
+        let batch = self.download.lock().unwrap().assign(*peer.addr(), &missing);
+        if !batch.is_empty() {
+            peer.write(Message::GetBlocks(batch));
+        }
+    }
 }
 
 #[cfg(any(test,test_utilities))]
@@ -285,7 +482,10 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_bytes(bincode::serialize(&msg).unwrap())
+    }
+
+    fn send_bytes(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
@@ -301,8 +501,11 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
     let mempool = Mempool::new();
     let mempool = Arc::new(Mutex::new(mempool));
     let tip = blockchain.lock().unwrap().tip();
-    let block_state_map = Arc::new(Mutex::new(BlockState::new()));
-    let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, &block_state_map);
+    let mut block_state = BlockState::new();
+    block_state.block_state_map.insert(tip, crate::types::state::State::new());
+    let block_state_map = Arc::new(Mutex::new(block_state));
+    let download = Arc::new(Mutex::new(BlockDownload::new()));
+    let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, &block_state_map, &download);
     worker.start(); 
     (test_msg_sender, server_receiver, vec![tip])
 }
@@ -315,6 +518,10 @@ mod test {
     use crate::types::block::generate_random_block;
     use crate::types::hash::{Hashable, H256};
 
+    use crate::types::merkle::MerkleTree;
+    use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+
+    use super::super::compact_block::{CompactBlock, MAX_BLOCK_TRANSACTIONS};
     use super::super::message::Message;
     use super::generate_test_worker_and_start;
 
@@ -400,6 +607,15 @@ mod test {
     }
     #[test]
     #[timeout(60000)]
+    fn malformed_message_is_skipped() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        test_msg_sender.send_bytes(vec![0xff; 7]);
+        let random_block = generate_random_block(v.last().unwrap());
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(vec![random_block.hash()]));
+        assert!(matches!(peer_receiver.recv(), Message::GetBlocks(_)));
+    }
+    #[test]
+    #[timeout(60000)]
     fn reply_blocks() {
         let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
         let random_block = generate_random_block(v.last().unwrap());
@@ -413,6 +629,70 @@ mod test {
     }
     #[test]
     #[timeout(60000)]
+    //the orphan and its parent arrive in different messages
+    fn orphans_connect_across_messages() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let parent = generate_random_block(v.last().unwrap());
+        let child = generate_random_block(&parent.hash());
+        let grandchild = generate_random_block(&child.hash());
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![grandchild.clone()]));
+        if let Message::GetBlocks(v) = peer_receiver.recv() {
+            assert_eq!(v, vec![child.hash()]);
+        } else {
+            panic!();
+        }
+        test_msg_sender.send(Message::Blocks(vec![child.clone()]));
+        test_msg_sender.send(Message::Blocks(vec![parent.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewBlockHashes(v) = reply {
+            assert_eq!(v, vec![parent.hash(), child.hash(), grandchild.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn compact_block_requests_missing_transactions() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        // an empty block is rebuilt from the header alone, and relayed
+        let empty = generate_random_block(v.last().unwrap());
+        test_msg_sender.send(Message::CompactBlock(CompactBlock::new(&empty)));
+        if let Some(Message::NewBlockHashes(v)) = server_receiver.recv() {
+            assert_eq!(v, vec![empty.hash()]);
+        } else {
+            panic!();
+        }
+
+        let mut block = generate_random_block(&empty.hash());
+        block.content.transactions.push(SignedTransaction {
+            transaction: generate_random_transaction(),
+            signature: vec![],
+            public_key: vec![],
+        });
+        block.header.merkle_root = MerkleTree::new(&block.content.transactions).root();
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        let mut peer_receiver = test_msg_sender.send(Message::CompactBlock(CompactBlock::new(&block)));
+        if let Message::GetBlockTransactions(hash, indexes) = peer_receiver.recv() {
+            assert_eq!(hash, block.hash());
+            assert_eq!(indexes, vec![0]);
+        } else {
+            panic!();
+        }
+
+        // a block with more transactions than we would mine is fetched whole
+        let mut oversized = CompactBlock::new(&generate_random_block(v.last().unwrap()));
+        oversized.short_ids = vec![0; MAX_BLOCK_TRANSACTIONS + 1];
+        let mut peer_receiver = test_msg_sender.send(Message::CompactBlock(oversized.clone()));
+        if let Message::GetBlocks(v) = peer_receiver.recv() {
+            assert_eq!(v, vec![oversized.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
     //test sending blocks that are already in the chain and new blocks together
     fn reply_blocks_existing_blocks() {
         let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
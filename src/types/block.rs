@@ -3,22 +3,145 @@ use crate::types::hash::{H256, Hashable};
 use crate::types::transaction::SignedTransaction;  
 use std::time::{SystemTime, UNIX_EPOCH};
 use sha2::{Sha256, Digest};
-use rand::Rng;
 use crate::types::merkle::MerkleTree;
 
 use std::collections::HashMap;
-use crate::types::address::Address;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use log::{error, info, warn};
+use crate::types::state::State;
+use crate::blockchain::Blockchain;
+
+/// Magic bytes at the start of a state snapshot
+const SNAPSHOT_MAGIC: &[u8; 4] = b"STAT";
+/// Version of the snapshot format, bumped whenever `State` serialization changes. A snapshot
+/// of another version is ignored and its state recomputed.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+/// Size of the snapshot header (magic + format version)
+const SNAPSHOT_HEADER_LEN: usize = 8;
 
 pub struct BlockState {
-    //block hash -> block state (account address -> (account nonce, account balance))
-    pub block_state_map: HashMap<H256, HashMap<Address, (u32, u32)>>
+    //block hash -> account state after that block
+    pub block_state_map: HashMap<H256, State>,
+    //directory where state snapshots are written, if any
+    snapshot_dir: Option<PathBuf>,
+    //a snapshot is written for every block whose height is a multiple of this (0 disables)
+    snapshot_interval: u64,
 }
 
 impl BlockState {
     pub fn new() -> Self {
         return BlockState {
-            block_state_map: HashMap::<H256, HashMap<Address, (u32, u32)>>::new()
+            block_state_map: HashMap::new(),
+            snapshot_dir: None,
+            snapshot_interval: 0,
+        }
+    }
+
+    /// Open the state snapshots in `dir` and rebuild the state of every block in `blockchain`.
+    /// Blocks without a snapshot get their state recomputed by re-executing their transactions
+    /// on top of their parent's state, so the work starts from the nearest snapshot. Every
+    /// stored block was valid when it was inserted, so a block whose state cannot be rebuilt
+    /// means the store does not match `genesis_state`, and opening fails.
+    pub fn open(
+        dir: PathBuf,
+        snapshot_interval: u64,
+        blockchain: &Blockchain,
+        genesis_state: State,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut block_state = BlockState {
+            block_state_map: HashMap::new(),
+            snapshot_dir: Some(dir),
+            snapshot_interval,
+        };
+
+        // parents always have a lower height than their children
+        let mut blocks: Vec<(&H256, &u64)> = blockchain.heights.iter().collect();
+        blocks.sort_by_key(|(_, height)| **height);
+
+        let mut loaded = 0;
+        let mut recomputed = 0;
+        for (hash, height) in blocks {
+            if *height == 0 {
+                block_state.block_state_map.insert(*hash, genesis_state.clone());
+                continue;
+            }
+            if let Some(state) = block_state.read_snapshot(hash) {
+                block_state.block_state_map.insert(*hash, state);
+                loaded += 1;
+                continue;
+            }
+            let block = &blockchain.blocks[hash];
+            let state = match block_state.block_state_map.get(&block.get_parent()) {
+                Some(parent_state) => parent_state.apply_block(block),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("cannot rebuild state for block {:?}: parent state missing", hash),
+                    ));
+                }
+            };
+            let state = state.map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("cannot rebuild state for block {:?}: {}", hash, e))
+            })?;
+            block_state.insert(*hash, *height, state);
+            recomputed += 1;
         }
+        info!("Loaded {} state snapshots, recomputed {} block states", loaded, recomputed);
+        Ok(block_state)
+    }
+
+    /// Record the state after the block `hash` at `height`, writing a snapshot when it is due
+    pub fn insert(&mut self, hash: H256, height: u64, state: State) {
+        if self.snapshot_interval != 0 && height.is_multiple_of(self.snapshot_interval) {
+            if let Err(e) = self.write_snapshot(&hash, &state) {
+                error!("Failed to write state snapshot for block {:?}: {}", hash, e);
+            }
+        }
+        self.block_state_map.insert(hash, state);
+    }
+
+    fn snapshot_path(&self, hash: &H256) -> Option<PathBuf> {
+        self.snapshot_dir.as_ref().map(|dir| dir.join(format!("{}.state", hash)))
+    }
+
+    fn read_snapshot(&self, hash: &H256) -> Option<State> {
+        let bytes = fs::read(self.snapshot_path(hash)?).ok()?;
+        if bytes.len() < SNAPSHOT_HEADER_LEN || &bytes[0..4] != SNAPSHOT_MAGIC {
+            warn!("Ignoring state snapshot without a header for block {:?}", hash);
+            return None;
+        }
+        let version = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != SNAPSHOT_FORMAT_VERSION {
+            info!("Ignoring state snapshot of format version {} for block {:?}", version, hash);
+            return None;
+        }
+        match bincode::deserialize(&bytes[SNAPSHOT_HEADER_LEN..]) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Ignoring corrupt state snapshot for block {:?}: {}", hash, e);
+                None
+            }
+        }
+    }
+
+    fn write_snapshot(&self, hash: &H256, state: &State) -> io::Result<()> {
+        let path = match self.snapshot_path(hash) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let bytes = bincode::serialize(state)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // write to a temporary file first so a crash never leaves a half-written snapshot
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        io::Write::write_all(&mut file, SNAPSHOT_MAGIC)?;
+        io::Write::write_all(&mut file, &SNAPSHOT_FORMAT_VERSION.to_be_bytes())?;
+        io::Write::write_all(&mut file, &bytes)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)
     }
 }
 
@@ -72,12 +195,13 @@ impl Block {
     }
 }
 
+/// Generate a block on top of `parent` that meets the genesis difficulty, so it passes
+/// validation on top of a fresh blockchain
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
     let nonce: u32 = rand::random();
     let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-    let mut rng = rand::thread_rng();
-    let difficulty = H256::from(rng.gen::<[u8; 32]>());
+    let difficulty: H256 = crate::blockchain::DIFFICULTY.into();
     let merkle_tree_ = MerkleTree::new(&Vec::<SignedTransaction>::new());
 
     let header = Header {
@@ -92,10 +216,103 @@ pub fn generate_random_block(parent: &H256) -> Block {
         transactions: Vec::<SignedTransaction>::new(),  
     };
 
-    let new_block = Block {
+    let mut new_block = Block {
         header: header,
         content: content
     };
 
+    while new_block.hash() > difficulty {
+        new_block.header.nonce = new_block.header.nonce.wrapping_add(1);
+    }
+
     return new_block;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use crate::types::state::Account;
+    use crate::types::transaction::signed_transfer;
+    use ring::signature::Ed25519KeyPair;
+
+    fn block_with_transfer(parent: &H256, key: &Ed25519KeyPair, nonce: u32) -> Block {
+        let mut block = generate_random_block(parent);
+        block.content.transactions.push(signed_transfer(key, nonce, 10, 1));
+        block
+    }
+
+    #[test]
+    fn state_rebuilt_after_restart() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-state-{}", crate::types::hash::generate_random_hash()));
+        let key = key_pair::random();
+        let transfer = signed_transfer(&key, 1, 10, 1);
+        let (sender, receiver) = (transfer.transaction.sender, transfer.transaction.receiver);
+        let mut genesis_state = State::new();
+        genesis_state.credit(sender, 1000).unwrap();
+
+        let states = {
+            let mut blockchain = Blockchain::open(&dir.join("chain")).unwrap();
+            let mut block_state =
+                BlockState::open(dir.join("state"), 2, &blockchain, genesis_state.clone()).unwrap();
+            let mut parent = blockchain.tip();
+            for nonce in 1..=5 {
+                let block = block_with_transfer(&parent, &key, nonce);
+                let state = block_state.block_state_map[&parent].apply_block(&block).unwrap();
+                blockchain.insert(&block).unwrap();
+                block_state.insert(block.hash(), blockchain.heights[&block.hash()], state);
+                parent = block.hash();
+            }
+            block_state.block_state_map
+        };
+        // snapshots are only written at heights 2 and 4
+        assert_eq!(fs::read_dir(dir.join("state")).unwrap().count(), 2);
+
+        let blockchain = Blockchain::open(&dir.join("chain")).unwrap();
+        let block_state = BlockState::open(dir.join("state"), 2, &blockchain, genesis_state).unwrap();
+        assert_eq!(block_state.block_state_map, states);
+        let tip_state = &block_state.block_state_map[&blockchain.tip()];
+        assert_eq!(tip_state.get(&sender), Account { nonce: 5, balance: 945 });
+        assert_eq!(tip_state.get(&receiver), Account { nonce: 0, balance: 50 });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_of_another_version_is_recomputed() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-state-{}", crate::types::hash::generate_random_hash()));
+        let key = key_pair::random();
+        let mut genesis_state = State::new();
+        genesis_state.credit(signed_transfer(&key, 1, 10, 1).transaction.sender, 1000).unwrap();
+
+        let mut blockchain = Blockchain::open(&dir.join("chain")).unwrap();
+        let mut block_state = BlockState::open(dir.join("state"), 1, &blockchain, genesis_state.clone()).unwrap();
+        let block = block_with_transfer(&blockchain.tip(), &key, 1);
+        let state = block_state.block_state_map[&blockchain.tip()].apply_block(&block).unwrap();
+        blockchain.insert(&block).unwrap();
+        block_state.insert(block.hash(), 1, state.clone());
+
+        // a snapshot that decodes, but was written in another format
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend_from_slice(&(SNAPSHOT_FORMAT_VERSION + 1).to_be_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&State::new()).unwrap());
+        fs::write(block_state.snapshot_path(&block.hash()).unwrap(), bytes).unwrap();
+
+        let block_state = BlockState::open(dir.join("state"), 1, &blockchain, genesis_state).unwrap();
+        assert_eq!(block_state.block_state_map[&block.hash()], state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_fails_when_a_state_cannot_be_rebuilt() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-state-{}", crate::types::hash::generate_random_hash()));
+        let key = key_pair::random();
+        let mut blockchain = Blockchain::open(&dir.join("chain")).unwrap();
+        let block = block_with_transfer(&blockchain.tip(), &key, 1);
+        blockchain.insert(&block).unwrap();
+
+        // the sender has nothing to transfer in this genesis state
+        let result = BlockState::open(dir.join("state"), 0, &blockchain, State::new());
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,198 @@
+use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+use super::address::Address;
+use super::block::Block;
+use super::hash::{H256, Hashable};
+use super::transaction::SignedTransaction;
+
+/// What the state knows about one account
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Account {
+    /// Nonce of the last transaction sent from this account; the next one must use `nonce + 1`
+    pub nonce: u32,
+    pub balance: u64,
+}
+
+/// Why a transaction or block cannot be applied to a state
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    /// The transaction nonce is not the sender's next nonce
+    WrongNonce { sender: Address, expected: u32, found: u32 },
+    /// The sender cannot pay the transaction's value plus its fee
+    InsufficientBalance { sender: Address, balance: u64, cost: u64 },
+    /// A balance, nonce or the transaction's cost does not fit in its integer type
+    Overflow(Address),
+    /// A coinbase transaction appears anywhere but first in a block
+    MisplacedCoinbase(H256),
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StateError::WrongNonce { sender, expected, found } => {
+                write!(f, "nonce {} of sender {} does not match expected {}", found, sender, expected)
+            }
+            StateError::InsufficientBalance { sender, balance, cost } => {
+                write!(f, "sender {} cannot pay {} with balance {}", sender, cost, balance)
+            }
+            StateError::Overflow(address) => write!(f, "balance, nonce or transaction cost of {} would overflow", address),
+            StateError::MisplacedCoinbase(tx) => write!(f, "coinbase transaction {} is not first in the block", tx),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// The nonce and balance of every account, as of some block
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct State {
+    accounts: HashMap<Address, Account>,
 }
 
 impl State {
     pub fn new() -> Self {
-        Self {}
+        Self { accounts: HashMap::new() }
     }
 
+    /// The account at `address`; accounts that never appeared have nonce and balance 0
+    pub fn get(&self, address: &Address) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    /// Every account that appeared in the state
+    pub fn iter(&self) -> impl Iterator<Item = (&Address, &Account)> {
+        self.accounts.iter()
+    }
+
+    /// Add `amount` to the balance of `address`, for the initial coin offering
+    pub fn credit(&mut self, address: Address, amount: u64) -> Result<(), StateError> {
+        let mut account = self.get(&address);
+        account.balance = account.balance.checked_add(amount).ok_or(StateError::Overflow(address))?;
+        self.accounts.insert(address, account);
+        Ok(())
+    }
+
+    /// Apply a single transaction. A coinbase mints its value to its receiver; any other
+    /// transaction must carry the sender's next nonce and the sender must be able to pay its
+    /// value plus fee. The fee leaves the sender here and reaches the miner through the coinbase.
+    /// The state is left untouched if the transaction cannot be applied.
+    pub fn apply_transaction(&mut self, tx: &SignedTransaction) -> Result<(), StateError> {
+        let transaction = &tx.transaction;
+        if tx.is_coinbase() {
+            return self.credit(transaction.receiver, transaction.value);
+        }
+
+        let sender = transaction.sender;
+        let mut sender_account = self.get(&sender);
+        let expected = sender_account.nonce.checked_add(1).ok_or(StateError::Overflow(sender))?;
+        if transaction.account_nonce != expected {
+            return Err(StateError::WrongNonce { sender, expected, found: transaction.account_nonce });
+        }
+        let cost = transaction.value.checked_add(transaction.fee).ok_or(StateError::Overflow(sender))?;
+        sender_account.balance = sender_account.balance.checked_sub(cost).ok_or(
+            StateError::InsufficientBalance { sender, balance: sender_account.balance, cost },
+        )?;
+        sender_account.nonce = transaction.account_nonce;
+
+        let receiver = transaction.receiver;
+        let mut receiver_account = if receiver == sender { sender_account } else { self.get(&receiver) };
+        receiver_account.balance = receiver_account.balance
+            .checked_add(transaction.value)
+            .ok_or(StateError::Overflow(receiver))?;
+
+        self.accounts.insert(sender, sender_account);
+        self.accounts.insert(receiver, receiver_account);
+        Ok(())
+    }
+
+    /// The state after executing the transactions of `block` on top of this one
+    pub fn apply_block(&self, block: &Block) -> Result<State, StateError> {
+        let mut state = self.clone();
+        for (index, tx) in block.content.transactions.iter().enumerate() {
+            if tx.is_coinbase() && index != 0 {
+                return Err(StateError::MisplacedCoinbase(tx.hash()));
+            }
+            state.apply_transaction(tx)?;
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use crate::types::transaction::signed_transfer;
+
+    #[test]
+    fn transfer_moves_value_and_fee() {
+        let key = key_pair::random();
+        let transfer = signed_transfer(&key, 1, 30, 2);
+        let (s, r) = (transfer.transaction.sender, transfer.transaction.receiver);
+        let mut state = State::new();
+        state.credit(s, 100).unwrap();
+        state.apply_transaction(&transfer).unwrap();
+        assert_eq!(state.get(&s), Account { nonce: 1, balance: 68 });
+        assert_eq!(state.get(&r), Account { nonce: 0, balance: 30 });
+
+        // sending to oneself only costs the fee
+        let mut to_self = signed_transfer(&key, 2, 30, 2);
+        to_self.transaction.receiver = s;
+        state.apply_transaction(&to_self).unwrap();
+        assert_eq!(state.get(&s), Account { nonce: 2, balance: 66 });
+    }
+
+    #[test]
+    fn rejected_transactions_leave_state_untouched() {
+        let key = key_pair::random();
+        let valid = signed_transfer(&key, 1, 10, 0);
+        let (s, r) = (valid.transaction.sender, valid.transaction.receiver);
+        let mut state = State::new();
+        state.credit(s, 100).unwrap();
+        state.credit(r, u64::MAX - 10).unwrap();
+        let before = state.clone();
+
+        assert_eq!(
+            state.apply_transaction(&signed_transfer(&key, 2, 1, 0)),
+            Err(StateError::WrongNonce { sender: s, expected: 1, found: 2 })
+        );
+        assert_eq!(
+            state.apply_transaction(&signed_transfer(&key, 1, 100, 1)),
+            Err(StateError::InsufficientBalance { sender: s, balance: 100, cost: 101 })
+        );
+        assert_eq!(state.apply_transaction(&signed_transfer(&key, 1, 20, 0)), Err(StateError::Overflow(r)));
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn overflowing_block_is_rejected() {
+        let key = key_pair::random();
+        let transfer = signed_transfer(&key, 1, u64::MAX, 0);
+        let (s, r) = (transfer.transaction.sender, transfer.transaction.receiver);
+        let mut state = State::new();
+        state.credit(s, u64::MAX).unwrap();
+        let mut block = crate::types::block::generate_random_block(&H256::default());
+        block.content.transactions.push(transfer);
+        // the coinbase credits the receiver first, and the transfer on top of it pushes the
+        // receiver past the largest amount
+        block.content.transactions.insert(0, SignedTransaction::coinbase(r, 1, 1));
+        assert_eq!(state.apply_block(&block), Err(StateError::Overflow(r)));
+
+        block.content.transactions[1] = signed_transfer(&key, 1, u64::MAX, 1);
+        assert_eq!(state.apply_block(&block), Err(StateError::Overflow(s)));
+    }
+
+    #[test]
+    fn block_applies_coinbase_first() {
+        let miner: Address = [3; 20].into();
+        let mut block = crate::types::block::generate_random_block(&H256::default());
+        block.content.transactions.push(SignedTransaction::coinbase(miner, 50, 1));
+        let state = State::new().apply_block(&block).unwrap();
+        assert_eq!(state.get(&miner), Account { nonce: 0, balance: 50 });
+
+        let second = SignedTransaction::coinbase(miner, 50, 1);
+        block.content.transactions.push(second.clone());
+        assert_eq!(State::new().apply_block(&block), Err(StateError::MisplacedCoinbase(second.hash())));
+    }
 }
@@ -6,14 +6,16 @@ use ring::signature::{Ed25519KeyPair, Signature, UnparsedPublicKey, KeyPair, ED2
 use rand::Rng;
 use bincode;
 
-use std::collections::HashMap;
+use crate::types::state::State;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
     pub sender: Address,
     pub receiver: Address,
-    pub value: u32,
+    pub value: u64,
     pub account_nonce: u32,
+    /// Paid by the sender on top of `value`, collected by the miner that includes the transaction
+    pub fee: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -30,9 +32,32 @@ impl Hashable for SignedTransaction {
     }
 }
 
+impl SignedTransaction {
+    /// Create the coinbase transaction of the block at `height`, minting `value` to `miner`.
+    /// A coinbase has the all-zero sender, carries the block height as its nonce (so every
+    /// coinbase has a distinct hash) and is not signed.
+    pub fn coinbase(miner: Address, value: u64, height: u64) -> Self {
+        SignedTransaction {
+            transaction: Transaction {
+                sender: Address::default(),
+                receiver: miner,
+                value,
+                account_nonce: height as u32,
+                fee: 0,
+            },
+            signature: vec![],
+            public_key: vec![],
+        }
+    }
+
+    /// Whether this transaction mints new coins; only valid as the first transaction of a block
+    pub fn is_coinbase(&self) -> bool {
+        self.transaction.sender == Address::default()
+    }
+}
+
 pub struct ICO {
-    //account address -> (account nonce, account balance)
-    pub state: HashMap<Address, (u32, u32)>
+    pub state: State
 }
 
 impl ICO {
@@ -40,10 +65,10 @@ impl ICO {
         //Do Initial Coin Offering (ICO); hardcode an account using the given pubkey
         let account_address = Address::from_public_key_bytes(pubkey);
         let balance = 1000000;
-        let mut map = HashMap::new();
-        map.insert(account_address, (0, balance));
+        let mut state = State::new();
+        state.credit(account_address, balance).expect("ICO balance overflows");
         return ICO {
-            state: map
+            state
         }
     }
 }
@@ -61,6 +86,43 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     public_key.verify(&serialized_transaction, signature).is_ok()
 }
 
+/// Why a signed transaction is not authorized by its sender
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureError {
+    /// The public key does not hash to the sender, so whoever signed does not own the account
+    WrongSender { sender: Address, signer: Address },
+    /// The signature does not verify under the public key
+    InvalidSignature,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SignatureError::WrongSender { sender, signer } => {
+                write!(f, "transaction from {} is signed by the key of {}", sender, signer)
+            }
+            SignatureError::InvalidSignature => write!(f, "signature does not verify"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Check that the sender of `tx` signed it: the public key must be the one the sender address
+/// is derived from, and the signature must verify under it. Every signed transaction we pool,
+/// mine or accept in a block goes through this.
+pub fn verify_signed(tx: &SignedTransaction) -> Result<(), SignatureError> {
+    let sender = tx.transaction.sender;
+    let signer = Address::from_public_key_bytes(&tx.public_key);
+    if signer != sender {
+        return Err(SignatureError::WrongSender { sender, signer });
+    }
+    if !verify(&tx.transaction, &tx.public_key, &tx.signature) {
+        return Err(SignatureError::InvalidSignature);
+    }
+    Ok(())
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_transaction() -> Transaction {
     let mut rng = rand::thread_rng();
@@ -75,12 +137,32 @@ pub fn generate_random_transaction() -> Transaction {
 
     // Generate a random value for the transaction
     let value = rng.gen_range(1..1000);
+    let fee = rng.gen_range(0..10);
 
     Transaction {
         sender,
         receiver,
         value,
         account_nonce:0,
+        fee,
+    }
+}
+
+/// A transfer of `value` plus `fee` from the owner of `key`, with nonce `nonce`, to a fixed
+/// receiver, signed so that it passes `verify_signed`
+#[cfg(test)]
+pub fn signed_transfer(key: &Ed25519KeyPair, nonce: u32, value: u64, fee: u64) -> SignedTransaction {
+    let transaction = Transaction {
+        sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+        receiver: [9; 20].into(),
+        value,
+        account_nonce: nonce,
+        fee,
+    };
+    SignedTransaction {
+        signature: sign(&transaction, key).as_ref().to_vec(),
+        public_key: key.public_key().as_ref().to_vec(),
+        transaction,
     }
 }
 
@@ -110,6 +192,36 @@ mod tests {
         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
     }
+
+    #[test]
+    fn forged_sender_is_rejected() {
+        let owner = key_pair::random();
+        let forger = key_pair::random();
+        let mut t = generate_random_transaction();
+        t.sender = Address::from_public_key_bytes(owner.public_key().as_ref());
+        let signed = |key: &Ed25519KeyPair| SignedTransaction {
+            transaction: t.clone(),
+            signature: sign(&t, key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+        };
+        assert_eq!(verify_signed(&signed(&owner)), Ok(()));
+
+        // the forger's signature is valid under its own key, but that key does not own the sender
+        let forged = signed(&forger);
+        assert!(verify(&forged.transaction, &forged.public_key, &forged.signature));
+        assert_eq!(
+            verify_signed(&forged),
+            Err(SignatureError::WrongSender {
+                sender: t.sender,
+                signer: Address::from_public_key_bytes(forger.public_key().as_ref()),
+            })
+        );
+
+        // the owner's key with someone else's signature
+        let mut swapped = signed(&forger);
+        swapped.public_key = owner.public_key().as_ref().to_vec();
+        assert_eq!(verify_signed(&swapped), Err(SignatureError::InvalidSignature));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file